@@ -8,12 +8,16 @@
 
 extern crate fsuipc;
 
+#[cfg(windows)]
 use std::io;
 use std::process;
 
+#[cfg(windows)]
 use fsuipc::*;
+#[cfg(windows)]
 use fsuipc::user::*;
 
+#[cfg(windows)]
 fn main() {
     match run() {
         Ok(_) => process::exit(0),
@@ -24,6 +28,13 @@ fn main() {
     }
 }
 
+#[cfg(not(windows))]
+fn main() {
+    println!("This example requires FSUIPC running on Windows");
+    process::exit(-1);
+}
+
+#[cfg(windows)]
 fn run() -> io::Result<()> {
     let mut handle = UserHandle::new()?;
     let mut session = handle.session();
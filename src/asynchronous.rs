@@ -0,0 +1,319 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+
+use super::{BatchSession, Error, Exchange, HandleRef, OffsetValue, Result};
+use super::safe::{ReadToken, Results, SafeSession};
+
+/// The state shared between a `Processing` future and the worker thread
+struct Shared {
+    result: Option<Result<Results>>,
+    waker: Option<Waker>,
+}
+
+/// A session sent to the worker thread to be processed
+struct Job {
+    session: Option<SafeSession<BatchSession<Detached>>>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+// The read destinations of the session point to the heap contents of its read buffers, which
+// are moved along with it. Thus, the job can be processed in another thread.
+unsafe impl Send for Job {}
+
+impl Job {
+    fn process<E: Exchange>(mut self, handle: &mut E) {
+        if let Some(session) = self.session.take() {
+            let result = session.map_session(|session| session.rebind(&mut *handle)).process();
+            self.resolve(result);
+        }
+    }
+
+    fn resolve(&self, result: Result<Results>) {
+        if let Ok(mut shared) = self.shared.lock() {
+            if shared.result.is_none() {
+                shared.result = Some(result);
+                if let Some(waker) = shared.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+// A job dropped without being processed, because the worker thread stopped or panicked while
+// processing it, resolves its future with an error instead of leaving it pending forever.
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.resolve(Err(worker_gone()));
+    }
+}
+
+fn worker_gone() -> Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "worker thread of async handle is gone").into()
+}
+
+/// The handle of an `AsyncSession` until it is sent to the worker thread
+/// The requests are only queued on it. They are exchanged by the worker thread, which rebinds
+/// the session to the handle it owns, so exchanging with this one always fails.
+struct Detached {
+    buffer_len: usize,
+}
+
+impl Exchange for Detached {
+    fn buffer_len(&self) -> usize { self.buffer_len }
+
+    fn exchange(&mut self, _: &mut [u8]) -> Result<()> {
+        Err(worker_gone())
+    }
+}
+
+impl HandleRef for Detached {
+    type Target = Detached;
+
+    fn with_handle<R, F: FnOnce(&mut Detached) -> R>(&mut self, f: F) -> R { f(self) }
+}
+
+/// A handle that exchanges data with FSUIPC in a dedicated worker thread
+/// The worker thread owns the wrapped handle, and it processes the sessions one after another.
+/// Processing a session returns a future that is resolved by the worker thread, so the caller
+/// is not blocked while FSUIPC responds.
+pub struct AsyncHandle<E> {
+    capacity: usize,
+    jobs: Option<Sender<Job>>,
+    worker: Option<JoinHandle<E>>,
+}
+
+impl<E: Exchange + Send + 'static> AsyncHandle<E> {
+    /// Create a new async handle, moving the given handle into a new worker thread
+    pub fn new(mut handle: E) -> Self {
+        let capacity = handle.buffer_len();
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let worker = thread::spawn(move || {
+            for job in receiver {
+                job.process(&mut handle);
+            }
+            handle
+        });
+        AsyncHandle { capacity, jobs: Some(jobs), worker: Some(worker) }
+    }
+
+    /// Create a new session
+    /// If the worker thread is gone, processing the session fails with an I/O error.
+    pub fn session(&self) -> AsyncSession {
+        let detached = Detached { buffer_len: self.capacity };
+        AsyncSession {
+            jobs: self.jobs.clone(),
+            session: SafeSession::new(BatchSession::new(detached, self.capacity)),
+        }
+    }
+
+    /// Stop the worker thread once the pending sessions are processed, returning the handle
+    /// It fails with an I/O error if the worker thread panicked, losing the handle.
+    pub fn shutdown(mut self) -> Result<E> {
+        self.stop().unwrap_or_else(|| Err(worker_gone()))
+    }
+}
+
+impl<E> AsyncHandle<E> {
+    fn stop(&mut self) -> Option<Result<E>> {
+        self.jobs.take();
+        self.worker.take().map(|worker| worker.join().map_err(|_| worker_gone()))
+    }
+}
+
+impl<E> Drop for AsyncHandle<E> {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// A session processed by the worker thread of an `AsyncHandle`
+/// As `SafeSession`, the read operations return a `ReadToken` to obtain the value from the
+/// `Results` after processing.
+pub struct AsyncSession {
+    jobs: Option<Sender<Job>>,
+    session: SafeSession<BatchSession<Detached>>,
+}
+
+impl AsyncSession {
+    /// Enable or disable splitting the requests in several exchanges
+    /// When enabled, the requests that do not fit in the buffer are sent in further exchanges
+    /// instead of failing with `Error::BufferOverflow`.
+    pub fn set_auto_split(&mut self, enabled: bool) {
+        self.session.session_mut().set_auto_split(enabled);
+    }
+
+    /// Enable or disable coalescing reads of contiguous or overlapping offsets
+    /// When enabled, consecutive reads that are not separated by writes are merged into as few
+    /// requests as possible, reducing the overhead of many small reads.
+    pub fn set_coalesce_reads(&mut self, enabled: bool) {
+        self.session.session_mut().set_coalesce_reads(enabled);
+    }
+
+    /// Request to read from the given offset
+    /// The length of the offset is inferred from the type `T`.
+    pub fn read<T: OffsetValue>(&mut self, offset: u16) -> Result<ReadToken<T>> {
+        self.session.read(offset)
+    }
+
+    /// Request to write the given value into the given offset
+    /// The length of the offset is inferred from the type `T`.
+    pub fn write<T: OffsetValue>(&mut self, offset: u16, value: &T) -> Result<usize> {
+        self.session.write(offset, value)
+    }
+
+    /// Send the requested operations to the worker thread to be processed
+    /// The returned future resolves to the read values once processed, or to an I/O error if
+    /// the worker thread is gone.
+    pub fn process(self) -> Processing {
+        let shared = Arc::new(Mutex::new(Shared { result: None, waker: None }));
+        let job = Job { session: Some(self.session), shared: shared.clone() };
+        if let Some(jobs) = self.jobs {
+            // A job that cannot be sent is dropped, which resolves the future with an error.
+            let _ = jobs.send(job);
+        }
+        Processing { shared }
+    }
+}
+
+/// A future resolved when the worker thread processes a session
+pub struct Processing {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Future for Processing {
+    type Output = Result<Results>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+    use std::thread::{self, Thread};
+
+    use super::*;
+    use super::super::Error;
+    use super::super::mock::MockHandle;
+
+    struct Unparker(Thread);
+
+    impl Wake for Unparker {
+        fn wake(self: Arc<Self>) { self.0.unpark(); }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(Unparker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn should_process_sessions_in_worker_thread() {
+        let mut mock = MockHandle::new();
+        mock.set(0x3304, &0x49505553u32);
+        let handle = AsyncHandle::new(mock);
+        let mut session = handle.session();
+        let fsuipc_ver = session.read::<u32>(0x3304).unwrap();
+        session.write(0x0330, &(1020u16 * 16)).unwrap();
+        let qnh = session.read::<u16>(0x0330).unwrap();
+        let results = block_on(session.process()).unwrap();
+        assert_eq!(results.get(&fsuipc_ver), 0x49505553);
+        assert_eq!(results.get(&qnh), 1020 * 16);
+        assert_eq!(results.processed_bytes(), 20 + 14 + 18 + 4);
+
+        let mock = handle.shutdown().unwrap();
+        assert_eq!(mock.get::<u16>(0x0330), 1020 * 16);
+        assert_eq!(mock.exchanges(), 1);
+    }
+
+    #[test]
+    fn should_apply_session_options_in_worker_thread() {
+        let mut mock = MockHandle::new();
+        mock.poke(0x0238, &[12, 34, 56]);
+        let handle = AsyncHandle::new(mock);
+        let mut session = handle.session();
+        session.set_coalesce_reads(true);
+        let hour = session.read::<u8>(0x0238).unwrap();
+        let minute = session.read::<u8>(0x0239).unwrap();
+        let second = session.read::<u8>(0x023a).unwrap();
+        let results = block_on(session.process()).unwrap();
+        assert_eq!((results.get(&hour), results.get(&minute), results.get(&second)), (12, 34, 56));
+        assert_eq!(results.processed_bytes(), 16 + 3 + 4);
+    }
+
+    #[test]
+    fn should_report_errors_from_worker_thread() {
+        let handle = AsyncHandle::new(MockHandle::new());
+        let mut session = handle.session();
+        session.read::<u32>(0xfffe).unwrap();
+        match block_on(session.process()) {
+            Err(Error::Rejected { code: 0 }) => {},
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    struct PanickingHandle;
+
+    impl Exchange for PanickingHandle {
+        fn buffer_len(&self) -> usize { 1024 }
+
+        fn exchange(&mut self, _: &mut [u8]) -> Result<()> {
+            panic!("exchange failed");
+        }
+    }
+
+    fn assert_worker_gone<T>(result: Result<T>) {
+        match result {
+            Err(Error::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::BrokenPipe),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn should_fail_when_worker_thread_is_gone() {
+        let handle = AsyncHandle::new(PanickingHandle);
+        let mut session = handle.session();
+        session.read::<u32>(0x3304).unwrap();
+        assert_worker_gone(block_on(session.process()));
+
+        let mut session = handle.session();
+        session.read::<u32>(0x3304).unwrap();
+        assert_worker_gone(block_on(session.process()));
+        assert_worker_gone(handle.shutdown());
+    }
+}
@@ -0,0 +1,409 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::ops::Range;
+use std::slice;
+
+use super::{Decoder, Exchange, Result, Session};
+use super::ipc::*;
+
+/// A request queued in a batch
+enum Request {
+    Read { offset: u16, len: usize, dest: *mut u8, decode: Option<Decoder> },
+    Write { offset: u16, data: Vec<u8> },
+}
+
+impl Request {
+    fn is_read(&self) -> bool {
+        match *self {
+            Request::Read { .. } => true,
+            Request::Write { .. } => false,
+        }
+    }
+
+    /// The number of bytes of the request once encoded
+    fn encoded_len(&self) -> usize {
+        match *self {
+            Request::Read { len, .. } => RSD_HEADER_LEN + len,
+            Request::Write { ref data, .. } => WSD_HEADER_LEN + data.len(),
+        }
+    }
+}
+
+/// A batch of requests to be exchanged with FSUIPC
+/// Requests are queued until the batch is processed. Then they are encoded in buffers of limited
+/// capacity, which are exchanged with FSUIPC one after another. If auto-split is disabled, all
+/// the requests must fit in a single buffer. Otherwise, the requests are split in as many
+/// buffers as needed preserving their order.
+///
+/// If read coalescing is enabled, consecutive reads of contiguous or overlapping offsets are
+/// merged into a single read request, and the data is scattered back into the original
+/// destinations. Reads are never merged across writes, so the results are the same.
+pub struct Batch {
+    requests: Vec<Request>,
+    chunks: Vec<usize>,
+    chunk_len: usize,
+    capacity: usize,
+    auto_split: bool,
+    coalesce_reads: bool,
+}
+
+impl Batch {
+    /// Create a new batch whose buffers are limited to `capacity` bytes
+    pub fn new(capacity: usize) -> Self {
+        Batch {
+            requests: Vec::new(),
+            chunks: Vec::new(),
+            chunk_len: 0,
+            capacity,
+            auto_split: false,
+            coalesce_reads: false,
+        }
+    }
+
+    pub fn set_auto_split(&mut self, enabled: bool) {
+        self.auto_split = enabled;
+    }
+
+    pub fn set_coalesce_reads(&mut self, enabled: bool) {
+        self.coalesce_reads = enabled;
+    }
+
+    /// The number of bytes left in the current buffer for further requests
+    pub fn remaining(&self) -> usize {
+        self.capacity.saturating_sub(self.chunk_len + TM_HEADER_LEN)
+    }
+
+    pub fn read(&mut self, offset: u16, dest: *mut u8, len: usize) -> Result<usize> {
+        self.push(Request::Read { offset, len, dest, decode: None })
+    }
+
+    /// Queue a read whose bytes are decoded in place by `decode` once they are copied
+    pub fn read_decoded(&mut self, offset: u16, dest: *mut u8, len: usize, decode: Decoder)
+        -> Result<usize>
+    {
+        self.push(Request::Read { offset, len, dest, decode: Some(decode) })
+    }
+
+    pub fn write(&mut self, offset: u16, src: *const u8, len: usize) -> Result<usize> {
+        let data = unsafe { slice::from_raw_parts(src, len).to_vec() };
+        self.push(Request::Write { offset, data })
+    }
+
+    fn push(&mut self, request: Request) -> Result<usize> {
+        let len = request.encoded_len();
+        if let Err(e) = ensure_room(len, self.capacity - self.chunk_len) {
+            if !self.auto_split || self.chunk_len == 0 || ensure_room(len, self.capacity).is_err() {
+                return Err(e);
+            }
+            self.chunks.push(self.requests.len());
+            self.chunk_len = 0;
+        }
+        self.chunk_len += len;
+        self.requests.push(request);
+        Ok(len)
+    }
+
+    fn chunk_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges = Vec::with_capacity(self.chunks.len() + 1);
+        let mut begin = 0;
+        for &end in &self.chunks {
+            ranges.push(begin..end);
+            begin = end;
+        }
+        ranges.push(begin..self.requests.len());
+        ranges
+    }
+
+    /// Process the requests, exchanging the buffers with FSUIPC using the given function
+    /// Each buffer starts with `prefix` bytes set to zero, followed by the requests and the
+    /// termination mark. The exchange function must leave the response in the same buffer.
+    /// It returns the number of bytes of requests processed in all the buffers.
+    ///
+    /// This is unsafe since the read data is copied into the raw destinations of the requests.
+    pub unsafe fn process<F>(&self, prefix: usize, mut exchange: F) -> Result<usize>
+        where F: FnMut(&mut [u8]) -> Result<()>
+    {
+        let mut nbytes = 0;
+        let mut buffer = Vec::with_capacity(prefix + self.capacity);
+        for chunk in self.chunk_ranges() {
+            buffer.clear();
+            buffer.resize(prefix, 0);
+            let mut destinations = Destinations::new();
+            let requests = &self.requests[chunk];
+            let mut i = 0;
+            while i < requests.len() {
+                match requests[i] {
+                    Request::Read { .. } => {
+                        let reads = requests[i..].iter().take_while(|r| r.is_read()).count();
+                        self.encode_reads(&requests[i..i + reads], &mut buffer, &mut destinations)?;
+                        i += reads;
+                    },
+                    Request::Write { offset, ref data } => {
+                        buffer.write_wsd(offset, data.as_ptr(), data.len())?;
+                        i += 1;
+                    },
+                }
+            }
+            buffer.write_header(&MsgHeader::TerminationMark)?;
+            nbytes += buffer.len() - prefix;
+            exchange(&mut buffer)?;
+            destinations.read_response(&buffer[prefix..])?;
+            for request in requests {
+                if let Request::Read { dest, decode: Some(decode), .. } = *request {
+                    decode(dest);
+                }
+            }
+        }
+        Ok(nbytes)
+    }
+
+    /// Encode a sequence of consecutive read requests, merging them if coalescing is enabled
+    fn encode_reads(&self,
+                    reads: &[Request],
+                    buffer: &mut Vec<u8>,
+                    destinations: &mut Destinations) -> Result<()> {
+        let mut reads: Vec<(usize, usize, *mut u8)> = reads.iter().filter_map(|r| match *r {
+            Request::Read { offset, len, dest, .. } => Some((offset as usize, len, dest)),
+            Request::Write { .. } => None,
+        }).collect();
+        if !self.coalesce_reads {
+            for (offset, len, dest) in reads {
+                let target = destinations.register(offset as u16, len, dest);
+                buffer.write_rsd(offset as u16, target, len)?;
+            }
+            return Ok(());
+        }
+        reads.sort_by_key(|&(offset, _, _)| offset);
+        let mut group = 0;
+        while group < reads.len() {
+            let begin = reads[group].0;
+            let mut end = begin + reads[group].1;
+            let mut next = group + 1;
+            while next < reads.len() && reads[next].0 <= end {
+                end = end.max(reads[next].0 + reads[next].1);
+                next += 1;
+            }
+            let target = if next == group + 1 {
+                destinations.register(begin as u16, end - begin, reads[group].2)
+            } else {
+                let parts = reads[group..next].iter().map(|&(offset, len, dest)| {
+                    Part { dest, skip: offset - begin, len }
+                });
+                destinations.register_parts(begin as u16, end - begin, parts)
+            };
+            buffer.write_rsd(begin as u16, target, end - begin)?;
+            group = next;
+        }
+        Ok(())
+    }
+}
+
+/// A reference to the handle a `BatchSession` exchanges its buffers with
+/// It is implemented for mutable references to any handle, and for the handles that can be
+/// owned by their sessions.
+pub trait HandleRef {
+    /// The type of the referenced handle
+    type Target: Exchange;
+
+    /// Run the given function with exclusive access to the referenced handle
+    /// All the exchanges of a session are done within a single call, so they are never
+    /// interleaved with the exchanges of other sessions of the same handle.
+    fn with_handle<R, F: FnOnce(&mut Self::Target) -> R>(&mut self, f: F) -> R;
+}
+
+impl<E: Exchange> HandleRef for &mut E {
+    type Target = E;
+
+    fn with_handle<R, F: FnOnce(&mut E) -> R>(&mut self, f: F) -> R { f(self) }
+}
+
+/// A session whose requests are queued in a batch until it is processed
+/// This is the session of all the handles of this crate. The requests are encoded and
+/// exchanged with the referenced handle when the session is processed.
+pub struct BatchSession<H> {
+    handle: H,
+    batch: Batch,
+}
+
+impl<H: HandleRef> BatchSession<H> {
+    /// Create a new session whose buffers are limited to `buffer_len` bytes
+    pub(crate) fn new(handle: H, buffer_len: usize) -> Self {
+        BatchSession { handle, batch: Batch::new(buffer_len) }
+    }
+
+    /// Enable or disable splitting the requests in several exchanges
+    /// When enabled, the requests that do not fit in the buffer are sent in further exchanges
+    /// instead of failing with `Error::BufferOverflow`.
+    pub fn set_auto_split(&mut self, enabled: bool) {
+        self.batch.set_auto_split(enabled);
+    }
+
+    /// Enable or disable coalescing reads of contiguous or overlapping offsets
+    /// When enabled, consecutive reads that are not separated by writes are merged into as few
+    /// requests as possible, reducing the overhead of many small reads.
+    pub fn set_coalesce_reads(&mut self, enabled: bool) {
+        self.batch.set_coalesce_reads(enabled);
+    }
+
+    /// The number of bytes left in the buffer for further requests
+    /// The bytes required to terminate the requests are not included.
+    pub fn remaining(&self) -> usize {
+        self.batch.remaining()
+    }
+
+    /// Move the queued requests and the options of this session to a session of another handle
+    #[cfg(feature = "async")]
+    pub(crate) fn rebind<G: HandleRef>(self, handle: G) -> BatchSession<G> {
+        BatchSession { handle, batch: self.batch }
+    }
+}
+
+impl<H: HandleRef> Session for BatchSession<H> {
+    fn read_bytes(&mut self, offset: u16, dest: *mut u8, len: usize) -> Result<usize> {
+        self.batch.read(offset, dest, len)
+    }
+
+    fn read_decoded(&mut self, offset: u16, dest: *mut u8, len: usize, decode: Decoder)
+        -> Result<usize>
+    {
+        self.batch.read_decoded(offset, dest, len, decode)
+    }
+
+    fn write_bytes(&mut self, offset: u16, src: *const u8, len: usize) -> Result<usize> {
+        self.batch.write(offset, src, len)
+    }
+
+    fn process(mut self) -> Result<usize> {
+        let batch = self.batch;
+        self.handle.with_handle(|handle| {
+            let prefix = handle.prefix_len();
+            unsafe { batch.process(prefix, |buffer| handle.exchange(buffer)) }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::Error;
+
+    #[test]
+    fn should_encode_requests_after_prefix() {
+        let mut batch = Batch::new(1024);
+        let value = 0x01020304u32;
+        batch.write(0x1000, &value as *const u32 as *const u8, 4).unwrap();
+        let mut exchanged = Vec::new();
+        let nbytes = unsafe {
+            batch.process(4, |buffer| { exchanged = buffer.to_vec(); Ok(()) }).unwrap()
+        };
+        assert_eq!(nbytes, 20);
+        assert_eq!(exchanged, vec![
+            0, 0, 0, 0,
+            2, 0, 0, 0, 0x00, 0x10, 0, 0, 4, 0, 0, 0, 4, 3, 2, 1,
+            0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn should_decode_reads_after_copying_them() {
+        unsafe fn invert(dest: *mut u8) {
+            *dest = !*dest;
+        }
+        let mut batch = Batch::new(1024);
+        let mut raw = 0u8;
+        let mut decoded = 0u8;
+        batch.read(0x1000, &mut raw, 1).unwrap();
+        batch.read_decoded(0x1000, &mut decoded, 1, invert).unwrap();
+        unsafe {
+            batch.process(0, |buffer| {
+                buffer[16] = 0x0f;
+                buffer[33] = 0x0f;
+                Ok(())
+            }).unwrap()
+        };
+        assert_eq!(raw, 0x0f);
+        assert_eq!(decoded, 0xf0);
+    }
+
+    #[test]
+    fn should_fail_when_full_without_auto_split() {
+        let mut batch = Batch::new(64);
+        let mut dest = [0u8; 40];
+        assert_eq!(batch.read(0x1000, dest.as_mut_ptr(), 20).unwrap(), 36);
+        match batch.read(0x1000, dest.as_mut_ptr(), 20) {
+            Err(Error::BufferOverflow { required: 36, available: 24 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_split_in_several_buffers_with_auto_split() {
+        let mut batch = Batch::new(64);
+        batch.set_auto_split(true);
+        let mut dest = [0u8; 60];
+        for i in 0..3 {
+            batch.read(0x1000, dest[i * 20..].as_mut_ptr(), 20).unwrap();
+        }
+        assert_eq!(batch.remaining(), 24);
+        let mut exchanges = 0;
+        let nbytes = unsafe {
+            batch.process(0, |buffer| {
+                exchanges += 1;
+                assert_eq!(buffer.len(), 40);
+                for b in &mut buffer[16..36] { *b = exchanges; }
+                Ok(())
+            }).unwrap()
+        };
+        assert_eq!(exchanges, 3);
+        assert_eq!(nbytes, 120);
+        assert_eq!(&dest[0..20], &[1u8; 20]);
+        assert_eq!(&dest[20..40], &[2u8; 20]);
+        assert_eq!(&dest[40..60], &[3u8; 20]);
+    }
+
+    #[test]
+    fn should_merge_overlapping_reads_when_coalescing() {
+        let mut batch = Batch::new(1024);
+        batch.set_coalesce_reads(true);
+        let mut first = [0u8; 4];
+        let mut second = [0u8; 4];
+        let mut apart = [0u8; 2];
+        batch.read(0x1002, second.as_mut_ptr(), 4).unwrap();
+        batch.read(0x1000, first.as_mut_ptr(), 4).unwrap();
+        batch.read(0x1008, apart.as_mut_ptr(), 2).unwrap();
+        let nbytes = unsafe {
+            batch.process(0, |buffer| {
+                assert_eq!(&buffer[0..4], &[1, 0, 0, 0]);
+                assert_eq!(&buffer[4..8], &[0x00, 0x10, 0, 0]);
+                assert_eq!(&buffer[8..12], &[6, 0, 0, 0]);
+                buffer[16..22].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+                assert_eq!(&buffer[22..26], &[1, 0, 0, 0]);
+                assert_eq!(&buffer[26..28], &[0x08, 0x10]);
+                buffer[38..40].copy_from_slice(&[7, 8]);
+                Ok(())
+            }).unwrap()
+        };
+        assert_eq!(nbytes, (16 + 6) + (16 + 2) + 4);
+        assert_eq!(first, [1, 2, 3, 4]);
+        assert_eq!(second, [3, 4, 5, 6]);
+        assert_eq!(apart, [7, 8]);
+    }
+
+    #[test]
+    fn should_fail_when_request_exceeds_capacity_with_auto_split() {
+        let mut batch = Batch::new(64);
+        batch.set_auto_split(true);
+        let mut dest = [0u8; 60];
+        match batch.read(0x1000, dest.as_mut_ptr(), 60) {
+            Err(Error::BufferOverflow { required: 76, available: 60 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
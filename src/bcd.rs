@@ -0,0 +1,300 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{Error, Result};
+
+offset_struct! {
+    /// A 4 digits value packed in BCD, as used by radio and transponder offsets
+    /// Each nibble of the raw value holds a decimal digit, the most significant first
+    /// (e.g., 0x7000 is 7000). The raw value is checked to be valid BCD when it is decoded.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Bcd {
+        pub raw: u16,
+    }
+}
+
+impl Bcd {
+    /// Encode the given value of 4 decimal digits at most
+    pub fn from_digits(value: u16) -> Result<Self> {
+        Self::encode(value as u32, 10)
+    }
+
+    /// Decode the digits of the value
+    pub fn digits(&self) -> Result<u16> {
+        self.decode(10)
+    }
+
+    /// Encode the given transponder code, whose 4 digits must be octal (e.g., 7000)
+    pub fn from_squawk(code: u16) -> Result<Self> {
+        Self::encode(code as u32, 8)
+    }
+
+    /// Decode the value as a transponder code, whose 4 digits must be octal
+    pub fn squawk(&self) -> Result<u16> {
+        self.decode(8)
+    }
+
+    /// Encode the given COM frequency in kHz, from 118000 to 136975
+    /// The leading 1 and the trailing digit are not stored, so the frequency is truncated to
+    /// 10 kHz. When decoded, frequencies ending in 2 or 7 are completed to the 25 kHz channel.
+    pub fn from_com_khz(khz: u32) -> Result<Self> {
+        check_range(khz, 118_000, 136_975)?;
+        Self::encode((khz - 100_000) / 10, 10)
+    }
+
+    /// Decode the value as a COM frequency in kHz
+    pub fn com_khz(&self) -> Result<u32> {
+        let khz = 100_000 + self.digits()? as u32 * 10;
+        match khz % 50 {
+            20 | 70 => Ok(khz + 5),
+            _ => Ok(khz),
+        }
+    }
+
+    /// Encode the given COM frequency in MHz (e.g., 118.275)
+    pub fn from_com_mhz(mhz: f64) -> Result<Self> {
+        Self::from_com_khz(mhz_to_khz(mhz)?)
+    }
+
+    /// Decode the value as a COM frequency in MHz
+    pub fn com_mhz(&self) -> Result<f64> {
+        Ok(self.com_khz()? as f64 / 1000.0)
+    }
+
+    /// Encode the given NAV frequency in kHz, from 108000 to 117950
+    pub fn from_nav_khz(khz: u32) -> Result<Self> {
+        check_range(khz, 108_000, 117_950)?;
+        Self::encode((khz - 100_000) / 10, 10)
+    }
+
+    /// Decode the value as a NAV frequency in kHz
+    pub fn nav_khz(&self) -> Result<u32> {
+        Ok(100_000 + self.digits()? as u32 * 10)
+    }
+
+    /// Encode the given NAV frequency in MHz (e.g., 110.30)
+    pub fn from_nav_mhz(mhz: f64) -> Result<Self> {
+        Self::from_nav_khz(mhz_to_khz(mhz)?)
+    }
+
+    /// Decode the value as a NAV frequency in MHz
+    pub fn nav_mhz(&self) -> Result<f64> {
+        Ok(self.nav_khz()? as f64 / 1000.0)
+    }
+
+    /// Encode the given ADF frequency in kHz, from 100.0 to 1799.9
+    /// It returns the main value, holding the hundreds, tens and units, and the extended
+    /// value, holding the thousands in the high byte and the tenths in the low byte.
+    pub fn from_adf_khz(khz: f64) -> Result<(Self, Self)> {
+        let tenths = (khz * 10.0).round();
+        if !(1000.0..=17999.0).contains(&tenths) {
+            return Err(Error::OutOfRange { value: khz });
+        }
+        let tenths = tenths as u32;
+        let main = Self::encode(tenths / 10 % 1000, 10)?;
+        let extension = Self::encode(tenths / 10_000 * 100 + tenths % 10, 10)?;
+        Ok((main, extension))
+    }
+
+    /// Decode the value as the main ADF frequency, completed with the given extended value
+    pub fn adf_khz(&self, extension: &Bcd) -> Result<f64> {
+        let main = self.digits()? as u32;
+        let extension = extension.digits()? as u32;
+        let tenths = extension / 100 * 10_000 + main * 10 + extension % 100;
+        Ok(tenths as f64 / 10.0)
+    }
+
+    fn encode(value: u32, radix: u32) -> Result<Self> {
+        let mut raw = 0;
+        let mut rest = value;
+        for i in 0..4 {
+            let digit = rest % 10;
+            if digit >= radix {
+                return Err(Error::OutOfRange { value: value as f64 });
+            }
+            raw |= (digit as u16) << (i * 4);
+            rest /= 10;
+        }
+        if rest != 0 {
+            return Err(Error::OutOfRange { value: value as f64 });
+        }
+        Ok(Bcd { raw })
+    }
+
+    fn decode(&self, radix: u16) -> Result<u16> {
+        let mut value = 0;
+        for i in (0..4).rev() {
+            let digit = (self.raw >> (i * 4)) & 0xf;
+            if digit >= radix {
+                return Err(Error::InvalidBcd { raw: self.raw });
+            }
+            value = value * 10 + digit;
+        }
+        Ok(value)
+    }
+}
+
+/// Convert the name of a COM channel in kHz (e.g., 118010) into the frequency it tunes in Hz
+/// With 8.33 kHz spacing, each 25 kHz block starting at `B` has the channels named `B` and
+/// `B + 5`, tuning `B`, and `B + 10` and `B + 15`, tuning 8.33 and 16.67 kHz above it. Names
+/// out of 118000 to 136990 or not in this grid fail with `Error::OutOfRange`.
+pub fn com_channel_to_hz(khz: u32) -> Result<u32> {
+    check_range(khz, 118_000, 136_990)?;
+    let step = match khz % 25 {
+        0 | 5 => 0,
+        10 => 1,
+        15 => 2,
+        _ => return Err(Error::OutOfRange { value: khz as f64 }),
+    };
+    Ok((khz - khz % 25) * 1000 + channel_offset_hz(step))
+}
+
+/// Convert a COM frequency in Hz (e.g., 118008333) into the name of its 8.33 kHz channel in kHz
+/// Frequencies are named after the 8.33 kHz channel tuning them, even if a 25 kHz channel
+/// tunes them too (e.g., 118000000 is 118005). Frequencies further than 1 Hz from the 8.33 kHz
+/// grid, or out of the COM band, fail with `Error::OutOfRange`.
+pub fn com_hz_to_channel(hz: u32) -> Result<u32> {
+    let mut block = hz / 25_000 * 25;
+    let mut step = (hz % 25_000 * 3 + 12_500) / 25_000;
+    let deviation = (hz % 25_000) as i64 - channel_offset_hz(step) as i64;
+    if step == 3 {
+        block += 25;
+        step = 0;
+    }
+    let khz = block + 5 * (step + 1);
+    if deviation.abs() > 1 || !(118_005..=136_990).contains(&khz) {
+        return Err(Error::OutOfRange { value: hz as f64 });
+    }
+    Ok(khz)
+}
+
+/// The offset in Hz of the given 8.33 kHz step from the beginning of its 25 kHz block
+fn channel_offset_hz(step: u32) -> u32 {
+    (step * 25_000 + 1) / 3
+}
+
+fn check_range(khz: u32, min: u32, max: u32) -> Result<()> {
+    if khz < min || khz > max {
+        return Err(Error::OutOfRange { value: khz as f64 });
+    }
+    Ok(())
+}
+
+fn mhz_to_khz(mhz: f64) -> Result<u32> {
+    let khz = (mhz * 1000.0).round();
+    if !(0.0..=u32::MAX as f64).contains(&khz) {
+        return Err(Error::OutOfRange { value: mhz });
+    }
+    Ok(khz as u32)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn should_convert_com_frequencies() {
+        assert_eq!(Bcd::from_com_mhz(118.275).unwrap().raw, 0x1827);
+        assert_eq!(Bcd { raw: 0x1827 }.com_mhz().unwrap(), 118.275);
+        assert_eq!(Bcd::from_com_khz(123_450).unwrap().raw, 0x2345);
+        assert_eq!(Bcd { raw: 0x2345 }.com_khz().unwrap(), 123_450);
+        assert_eq!(Bcd { raw: 0x3697 }.com_khz().unwrap(), 136_975);
+    }
+
+    #[test]
+    fn should_convert_nav_frequencies() {
+        assert_eq!(Bcd::from_nav_mhz(110.30).unwrap().raw, 0x1030);
+        assert_eq!(Bcd { raw: 0x0805 }.nav_khz().unwrap(), 108_050);
+        assert_eq!(Bcd { raw: 0x1795 }.nav_mhz().unwrap(), 117.95);
+    }
+
+    #[test]
+    fn should_convert_adf_frequencies() {
+        let (main, extension) = Bcd::from_adf_khz(1234.5).unwrap();
+        assert_eq!(main.raw, 0x0234);
+        assert_eq!(extension.raw, 0x0105);
+        assert_eq!(main.adf_khz(&extension).unwrap(), 1234.5);
+        assert_eq!(Bcd { raw: 0x0342 }.adf_khz(&Bcd::default()).unwrap(), 342.0);
+    }
+
+    #[test]
+    fn should_convert_squawk_codes() {
+        assert_eq!(Bcd::from_squawk(7000).unwrap().raw, 0x7000);
+        assert_eq!(Bcd { raw: 0x1234 }.squawk().unwrap(), 1234);
+        assert_eq!(Bcd::from_digits(9999).unwrap().raw, 0x9999);
+    }
+
+    #[test]
+    fn should_reject_out_of_range_values() {
+        for result in [
+            Bcd::from_squawk(7800),
+            Bcd::from_squawk(10000),
+            Bcd::from_com_mhz(117.975),
+            Bcd::from_com_mhz(137.0),
+            Bcd::from_nav_mhz(118.0),
+            Bcd::from_nav_mhz(-1.0),
+        ] {
+            match result {
+                Err(Error::OutOfRange { .. }) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        match Bcd::from_adf_khz(1800.0) {
+            Err(Error::OutOfRange { value }) => assert_eq!(value, 1800.0),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_convert_com_channels() {
+        assert_eq!(com_channel_to_hz(118_000).unwrap(), 118_000_000);
+        assert_eq!(com_channel_to_hz(118_005).unwrap(), 118_000_000);
+        assert_eq!(com_channel_to_hz(118_010).unwrap(), 118_008_333);
+        assert_eq!(com_channel_to_hz(118_015).unwrap(), 118_016_667);
+        assert_eq!(com_channel_to_hz(136_990).unwrap(), 136_991_667);
+        assert_eq!(com_hz_to_channel(118_000_000).unwrap(), 118_005);
+        assert_eq!(com_hz_to_channel(118_008_333).unwrap(), 118_010);
+        assert_eq!(com_hz_to_channel(118_008_334).unwrap(), 118_010);
+        assert_eq!(com_hz_to_channel(118_016_667).unwrap(), 118_015);
+        assert_eq!(com_hz_to_channel(118_024_999).unwrap(), 118_030);
+        assert_eq!(com_hz_to_channel(136_991_667).unwrap(), 136_990);
+    }
+
+    #[test]
+    fn should_reject_off_grid_com_channels() {
+        for result in [
+            com_channel_to_hz(118_020),
+            com_channel_to_hz(118_003),
+            com_channel_to_hz(117_990),
+            com_channel_to_hz(136_995),
+            com_hz_to_channel(118_004_000),
+            com_hz_to_channel(118_008_335),
+            com_hz_to_channel(117_991_667),
+            com_hz_to_channel(137_000_000),
+            com_hz_to_channel(u32::MAX),
+        ] {
+            match result {
+                Err(Error::OutOfRange { .. }) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_bcd() {
+        match (Bcd { raw: 0x7080 }).squawk() {
+            Err(Error::InvalidBcd { raw: 0x7080 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match (Bcd { raw: 0x12a4 }).com_khz() {
+            Err(Error::InvalidBcd { raw: 0x12a4 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+extern crate fsuipc;
+
+#[cfg(windows)]
+use std::env;
+#[cfg(windows)]
+use std::io;
+#[cfg(windows)]
+use std::net::TcpListener;
+use std::process;
+
+#[cfg(windows)]
+use fsuipc::bridge;
+#[cfg(windows)]
+use fsuipc::shared::SharedHandle;
+#[cfg(windows)]
+use fsuipc::user::UserHandle;
+
+#[cfg(windows)]
+fn main() {
+    match run() {
+        Ok(_) => process::exit(0),
+        Err(e) => {
+            println!("IO error: {:?}", e);
+            process::exit(-1);
+        },
+    }
+}
+
+#[cfg(not(windows))]
+fn main() {
+    println!("The bridge server requires FSUIPC running on Windows");
+    process::exit(-1);
+}
+
+// The bridge gives full access to FSUIPC, so it only listens on the loopback interface
+// unless an address is given to expose it to other hosts.
+#[cfg(windows)]
+fn run() -> io::Result<()> {
+    let addr = env::args().nth(1)
+        .unwrap_or_else(|| format!("127.0.0.1:{}", bridge::DEFAULT_PORT));
+    let handle = SharedHandle::new(UserHandle::new()?);
+    let listener = TcpListener::bind(&addr)?;
+    println!("Serving FSUIPC on {}", addr);
+    bridge::serve(&listener, handle)
+}
@@ -0,0 +1,244 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{BatchSession, Error, Exchange, Handle, Result};
+use super::ipc::check_request;
+use super::shared::SharedHandle;
+
+/// The TCP port the bridge server listens to by default
+pub const DEFAULT_PORT: u16 = 9017;
+
+// The bridge protocol is a sequence of IPC buffers sent by the client, each one answered by
+// the server with the response left by FSUIPC in the buffer. All integers are little-endian.
+//
+// * When a client connects, the server sends the maximum length of the buffers as u32.
+// * Requests are a u32 with the length of the buffer, followed by the buffer. The buffer
+//   contains the IPC messages and the termination mark, without the prefix of the handle.
+// * Responses are a u8 status. If it is `STATUS_OK`, it is followed by the response buffer,
+//   whose length is the same as the request. Otherwise, it is followed by an error kind as u8
+//   and two u64 parameters (see `Error::write_to()`).
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/// Serve the given handle to the clients connecting to the given listener
+/// Each client is served in its own thread. Their requests are serialised through the shared
+/// handle. It only returns if the listener fails.
+pub fn serve<E: Exchange + Send + 'static>(listener: &TcpListener, handle: SharedHandle<E>)
+    -> io::Result<()>
+{
+    loop {
+        let (stream, _) = listener.accept()?;
+        let mut handle = handle.clone();
+        thread::spawn(move || serve_client(stream, &mut handle));
+    }
+}
+
+/// Serve the given handle to a connected client until it disconnects
+/// The buffers sent by the client are checked before forwarding them to the handle: buffers
+/// longer than the handle accepts fail with `Error::BufferOverflow`, and malformed ones with
+/// `Error::TruncatedMessage` or `Error::ProtocolViolation`.
+pub fn serve_client<E: Exchange>(stream: TcpStream, handle: &mut E) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::new(stream);
+    let buffer_len = handle.buffer_len();
+    let prefix = handle.prefix_len();
+    output.write_u32::<LittleEndian>(buffer_len as u32)?;
+    output.flush()?;
+    let mut buffer = Vec::with_capacity(prefix + buffer_len);
+    loop {
+        let len = match input.read_u32::<LittleEndian>() {
+            Ok(len) => len as usize,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let result = if len > buffer_len {
+            // The oversized buffer is skipped without storing it, so the connection can go on.
+            io::copy(&mut input.by_ref().take(len as u64), &mut io::sink())?;
+            Err(Error::BufferOverflow { required: len, available: buffer_len })
+        } else {
+            buffer.clear();
+            buffer.resize(prefix + len, 0);
+            input.read_exact(&mut buffer[prefix..])?;
+            check_request(&buffer[prefix..]).and_then(|()| handle.exchange(&mut buffer))
+        };
+        match result {
+            Ok(()) => {
+                output.write_u8(STATUS_OK)?;
+                output.write_all(&buffer[prefix..])?;
+            },
+            Err(e) => {
+                output.write_u8(STATUS_ERROR)?;
+                e.write_to(&mut output)?;
+            },
+        }
+        output.flush()?;
+    }
+}
+
+/// A handle to FSUIPC through a bridge server
+/// The IPC buffers are forwarded to the bridge server, which exchanges them with the handle
+/// it serves. Thus, FSUIPC can be accessed from other hosts, even if they do not run Windows.
+pub struct RemoteHandle {
+    input: BufReader<TcpStream>,
+    output: BufWriter<TcpStream>,
+    buffer_len: usize,
+}
+
+impl RemoteHandle {
+    /// Connect to the bridge server at the given address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut input = BufReader::new(stream.try_clone()?);
+        let buffer_len = input.read_u32::<LittleEndian>()? as usize;
+        Ok(RemoteHandle { input, output: BufWriter::new(stream), buffer_len })
+    }
+
+    /// Set the time to wait for the response of the bridge server
+    /// If the response does not arrive in time, the exchange fails with an I/O error. Since
+    /// the response may still arrive later, the handle should not be used anymore.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.input.get_ref().set_read_timeout(timeout)?;
+        Ok(())
+    }
+}
+
+impl Exchange for RemoteHandle {
+    fn buffer_len(&self) -> usize { self.buffer_len }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.output.write_u32::<LittleEndian>(buffer.len() as u32)?;
+        self.output.write_all(buffer)?;
+        self.output.flush()?;
+        match self.input.read_u8()? {
+            STATUS_OK => {
+                self.input.read_exact(buffer)?;
+                Ok(())
+            },
+            _ => Err(Error::read_from(&mut self.input)?),
+        }
+    }
+}
+
+impl<'a> Handle<'a> for RemoteHandle {
+    type Sess = RemoteSession<'a>;
+
+    fn session(&'a mut self) -> RemoteSession<'a> {
+        let buffer_len = self.buffer_len;
+        BatchSession::new(self, buffer_len)
+    }
+}
+
+/// A session of a `RemoteHandle`
+pub type RemoteSession<'a> = BatchSession<&'a mut RemoteHandle>;
+
+#[cfg(test)]
+mod test {
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use super::super::Session;
+    use super::super::mock::MockHandle;
+
+    fn start_server(mock: MockHandle) -> (SharedHandle<MockHandle>, RemoteHandle) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = SharedHandle::new(mock);
+        {
+            let handle = handle.clone();
+            thread::spawn(move || serve(&listener, handle));
+        }
+        (handle, RemoteHandle::connect(addr).unwrap())
+    }
+
+    #[test]
+    fn should_forward_sessions_to_server() {
+        let mut mock = MockHandle::new();
+        mock.set(0x3304, &0x49505553u32);
+        let (server, mut remote) = start_server(mock);
+        let mut fsuipc_ver = 0u32;
+        let nbytes = {
+            let mut session = remote.session();
+            session.read(0x3304, &mut fsuipc_ver).unwrap();
+            session.write(0x0330, &(1020u16 * 16)).unwrap();
+            session.process().unwrap()
+        };
+        assert_eq!(nbytes, 20 + 14 + 4);
+        assert_eq!(fsuipc_ver, 0x49505553);
+        assert_eq!(server.lock().get::<u16>(0x0330), 1020 * 16);
+    }
+
+    #[test]
+    fn should_report_errors_of_server() {
+        let (_, mut remote) = start_server(MockHandle::with_buffer_len(64));
+        assert_eq!(remote.buffer_len(), 64);
+        let mut value = 0u32;
+        let mut session = remote.session();
+        session.read(0xfffe, &mut value).unwrap();
+        match session.process() {
+            Err(Error::Rejected { code: 0 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let mut buffer = vec![0; 128];
+        match remote.exchange(&mut buffer) {
+            Err(Error::BufferOverflow { required: 128, available: 64 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_reject_malformed_buffers() {
+        let (server, mut remote) = start_server(MockHandle::new());
+        let mut buffer = Vec::new();
+        buffer.write_u32::<LittleEndian>(2).unwrap();
+        buffer.write_u32::<LittleEndian>(0x0330).unwrap();
+        buffer.write_u32::<LittleEndian>(64).unwrap();
+        buffer.extend_from_slice(&[0; 4]);
+        match remote.exchange(&mut buffer) {
+            Err(Error::TruncatedMessage { offset_in_buffer: 0, required: 76, available: 16 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let mut buffer = vec![1, 0, 0, 0];
+        match remote.exchange(&mut buffer) {
+            Err(Error::TruncatedMessage { offset_in_buffer: 0, required: 16, available: 4 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(server.lock().exchanges(), 0);
+    }
+
+    #[test]
+    fn should_serve_several_clients() {
+        let (server, mut first) = start_server(MockHandle::new());
+        let addr = first.input.get_ref().peer_addr().unwrap();
+        let mut second = RemoteHandle::connect(addr).unwrap();
+        {
+            let mut session = first.session();
+            session.write(0x1000, &1u8).unwrap();
+            session.process().unwrap();
+        }
+        let mut value = 0u8;
+        {
+            let mut session = second.session();
+            session.read(0x1000, &mut value).unwrap();
+            session.process().unwrap();
+        }
+        assert_eq!(value, 1);
+        assert_eq!(server.lock().exchanges(), 2);
+    }
+}
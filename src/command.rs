@@ -0,0 +1,142 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{Error, Result};
+use super::string;
+
+/// The maximum length of a command, including the null terminator
+pub const MAX_COMMAND_LEN: usize = 128;
+
+/// A command for a Lua plugin run by FSUIPC
+/// Plugins are identified by the name of their file, without the `.lua` extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LuaCommand {
+    /// Run the plugin, passing the given parameter as `ipcPARAM`
+    Run(i32),
+    /// Run the plugin with tracing enabled, passing the given parameter as `ipcPARAM`
+    Debug(i32),
+    /// Kill the plugin if it is running
+    Kill,
+    /// Set the given flag of the plugin, from 0 to 255
+    Set(u8),
+    /// Clear the given flag of the plugin, from 0 to 255
+    Clear(u8),
+    /// Toggle the given flag of the plugin, from 0 to 255
+    Toggle(u8),
+    /// Pass the given value to the running plugin
+    Value(i32),
+}
+
+impl LuaCommand {
+    fn keyword(&self) -> &'static str {
+        match *self {
+            LuaCommand::Run(_) => "Lua",
+            LuaCommand::Debug(_) => "LuaDebug",
+            LuaCommand::Kill => "LuaKill",
+            LuaCommand::Set(_) => "LuaSet",
+            LuaCommand::Clear(_) => "LuaClear",
+            LuaCommand::Toggle(_) => "LuaToggle",
+            LuaCommand::Value(_) => "LuaValue",
+        }
+    }
+
+    fn param(&self) -> i32 {
+        match *self {
+            LuaCommand::Run(param) |
+            LuaCommand::Debug(param) |
+            LuaCommand::Value(param) => param,
+            LuaCommand::Set(flag) |
+            LuaCommand::Clear(flag) |
+            LuaCommand::Toggle(flag) => flag as i32,
+            LuaCommand::Kill => 0,
+        }
+    }
+}
+
+/// Encode the bytes to be written from the command parameter offset on
+/// The parameter precedes the command in the offsets, so both are written at once and
+/// FSUIPC always finds the parameter of the command when the command is executed.
+fn encode(param: i32, command: &str) -> Result<Vec<u8>> {
+    let mut bytes = param.to_le_bytes().to_vec();
+    bytes.extend(string::encode(command, MAX_COMMAND_LEN)?);
+    Ok(bytes)
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+        return Err(Error::InvalidCommandName { name: name.to_string() });
+    }
+    Ok(())
+}
+
+/// Encode the given command for the Lua plugin with the given name
+pub(crate) fn encode_lua(name: &str, command: LuaCommand) -> Result<Vec<u8>> {
+    check_name(name)?;
+    encode(command.param(), &format!("{} {}", command.keyword(), name))
+}
+
+/// Encode the execution of the given control of the macro file with the given name
+pub(crate) fn encode_macro(file: &str, control: &str, param: i32) -> Result<Vec<u8>> {
+    check_name(file)?;
+    check_name(control)?;
+    encode(param, &format!("{}:{}", file, control))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::{Handle, Session};
+    use super::super::mock::MockHandle;
+
+    #[test]
+    fn should_encode_lua_commands() {
+        let bytes = encode_lua("autobrake", LuaCommand::Set(3)).unwrap();
+        assert_eq!(bytes.len(), 4 + MAX_COMMAND_LEN);
+        assert_eq!(&bytes[..4], &[3, 0, 0, 0]);
+        assert_eq!(&bytes[4..21], b"LuaSet autobrake\0");
+
+        let bytes = encode_lua("autobrake", LuaCommand::Value(-2)).unwrap();
+        assert_eq!(&bytes[..4], &[0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(&bytes[4..23], b"LuaValue autobrake\0");
+    }
+
+    #[test]
+    fn should_encode_macros() {
+        let bytes = encode_macro("pmdg737", "apu_start", 1).unwrap();
+        assert_eq!(&bytes[..4], &[1, 0, 0, 0]);
+        assert_eq!(&bytes[4..22], b"pmdg737:apu_start\0");
+    }
+
+    #[test]
+    fn should_fail_to_encode_invalid_names() {
+        for name in &["", "my plugin", "file:control"] {
+            match encode_lua(name, LuaCommand::Kill) {
+                Err(Error::InvalidCommandName { name: ref n }) => assert_eq!(n, name),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        match encode_lua(&"x".repeat(124), LuaCommand::Run(0)) {
+            Err(Error::StringTooLong { max_len: 128, .. }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_send_lua_commands_with_their_parameters() {
+        let mut handle = MockHandle::new();
+        let nbytes = {
+            let mut session = handle.session();
+            session.lua("autobrake", LuaCommand::Run(2)).unwrap();
+            session.process().unwrap()
+        };
+        assert_eq!(nbytes, 12 + 4 + 128 + 4);
+        assert_eq!(handle.get::<i32>(0x0D6C), 2);
+        assert_eq!(handle.peek(0x0D70, 14), b"Lua autobrake\0");
+    }
+}
@@ -0,0 +1,189 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+use std::str::FromStr;
+
+use super::Error;
+
+macro_rules! control_table {
+    ($($(#[$doc:meta])* $variant:ident = $number:expr, $name:expr;)*) => {
+        /// A simulator control that can be sent with `Session::send_control()`
+        /// Common controls are declared in a table with their numbers and their names as listed
+        /// in the documentation of FSUIPC, so they can be configured and validated by name. Any
+        /// other control, including the ones added by FSUIPC (numbered from 1000 on), is
+        /// represented by `Other` with its number.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum Control {
+            $($(#[$doc])* $variant,)*
+            /// A control not declared in the table, with its number
+            /// Use `from_number()` to obtain it, so declared controls are never represented
+            /// by this variant.
+            Other(u32),
+        }
+
+        const CONTROLS: &[(Control, u32, &str)] = &[
+            $((Control::$variant, $number, $name),)*
+        ];
+    }
+}
+
+control_table! {
+    /// Toggle the strobe lights
+    StrobesToggle = 65560, "STROBES_TOGGLE";
+    /// Toggle the simulation pause
+    PauseToggle = 65561, "PAUSE_TOGGLE";
+    /// Toggle the landing gear
+    GearToggle = 65570, "GEAR_TOGGLE";
+    /// Toggle the autopilot master switch
+    ApMaster = 65580, "AP_MASTER";
+    /// Apply the brakes
+    Brakes = 65588, "BRAKES";
+    /// Apply the left brake
+    BrakesLeft = 65589, "BRAKES_LEFT";
+    /// Apply the right brake
+    BrakesRight = 65590, "BRAKES_RIGHT";
+    /// Move the elevator trim down
+    ElevTrimDn = 65607, "ELEV_TRIM_DN";
+    /// Move the elevator trim up
+    ElevTrimUp = 65615, "ELEV_TRIM_UP";
+    /// Set the COM1 frequency, with the parameter in BCD
+    ComRadioSet = 65707, "COM_RADIO_SET";
+    /// Set the NAV1 frequency, with the parameter in BCD
+    Nav1RadioSet = 65708, "NAV1_RADIO_SET";
+    /// Set the NAV2 frequency, with the parameter in BCD
+    Nav2RadioSet = 65709, "NAV2_RADIO_SET";
+    /// Set the transponder code, with the parameter in BCD
+    XpndrSet = 65715, "XPNDR_SET";
+    /// Toggle the panel lights
+    PanelLightsToggle = 65750, "PANEL_LIGHTS_TOGGLE";
+    /// Toggle the landing lights
+    LandingLightsToggle = 65751, "LANDING_LIGHTS_TOGGLE";
+    /// Toggle the parking brakes
+    ParkingBrakes = 65752, "PARKING_BRAKES";
+    /// Increment the heading bug
+    HeadingBugInc = 65879, "HEADING_BUG_INC";
+    /// Decrement the heading bug
+    HeadingBugDec = 65880, "HEADING_BUG_DEC";
+    /// Increment the autopilot altitude reference
+    ApAltVarInc = 65892, "AP_ALT_VAR_INC";
+    /// Decrement the autopilot altitude reference
+    ApAltVarDec = 65893, "AP_ALT_VAR_DEC";
+    /// Retract the landing gear
+    GearUp = 66079, "GEAR_UP";
+    /// Extend the landing gear
+    GearDown = 66080, "GEAR_DOWN";
+}
+
+impl Control {
+    /// The number of the control, as sent to the simulator
+    pub fn number(self) -> u32 {
+        match self {
+            Control::Other(number) => number,
+            control => control.entry().map_or(0, |c| c.1),
+        }
+    }
+
+    /// The name of the control, as listed in the documentation of FSUIPC
+    /// Controls not declared in the table have no name.
+    pub fn name(self) -> Option<&'static str> { self.entry().map(|c| c.2) }
+
+    /// Obtain the control with the given number
+    /// Numbers not declared in the table are returned as `Other`.
+    pub fn from_number(number: u32) -> Control {
+        CONTROLS.iter().find(|c| c.1 == number).map_or(Control::Other(number), |c| c.0)
+    }
+
+    /// Obtain the declared control with the given name, if any
+    /// Names are matched ignoring case.
+    pub fn from_name(name: &str) -> Option<Control> {
+        CONTROLS.iter().find(|c| c.2.eq_ignore_ascii_case(name)).map(|c| c.0)
+    }
+
+    /// Iterate over all the controls declared in the table
+    pub fn all() -> impl Iterator<Item=Control> {
+        CONTROLS.iter().map(|c| c.0)
+    }
+
+    fn entry(self) -> Option<&'static (Control, u32, &'static str)> {
+        CONTROLS.iter().find(|c| c.0 == self)
+    }
+}
+
+impl From<Control> for u32 {
+    fn from(control: Control) -> u32 { control.number() }
+}
+
+impl From<u32> for Control {
+    fn from(number: u32) -> Control { Control::from_number(number) }
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "{}", self.number()),
+        }
+    }
+}
+
+impl FromStr for Control {
+    type Err = Error;
+
+    /// Parse a control from its name, or from its number for any control
+    fn from_str(s: &str) -> Result<Control, Error> {
+        Control::from_name(s)
+            .or_else(|| s.parse().ok().map(Control::from_number))
+            .ok_or_else(|| Error::UnknownControl { name: s.to_string() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn should_convert_controls_to_numbers_and_names() {
+        assert_eq!(Control::GearToggle.number(), 65570);
+        assert_eq!(u32::from(Control::ApMaster), 65580);
+        assert_eq!(Control::ApMaster.name(), Some("AP_MASTER"));
+        assert_eq!(Control::GearDown.to_string(), "GEAR_DOWN");
+        assert_eq!(Control::from_number(66079), Control::GearUp);
+    }
+
+    #[test]
+    fn should_represent_other_controls_by_number() {
+        let control = Control::from_number(1070);
+        assert_eq!(control, Control::Other(1070));
+        assert_eq!(control.number(), 1070);
+        assert_eq!(control.name(), None);
+        assert_eq!(control.to_string(), "1070");
+        assert_eq!(Control::from(66587), Control::Other(66587));
+    }
+
+    #[test]
+    fn should_parse_controls_by_name() {
+        assert_eq!("GEAR_TOGGLE".parse::<Control>().unwrap(), Control::GearToggle);
+        assert_eq!("ap_master".parse::<Control>().unwrap(), Control::ApMaster);
+        assert_eq!("65580".parse::<Control>().unwrap(), Control::ApMaster);
+        assert_eq!("1070".parse::<Control>().unwrap(), Control::Other(1070));
+        match "GEAR_RETRACT".parse::<Control>() {
+            Err(Error::UnknownControl { ref name }) => assert_eq!(name, "GEAR_RETRACT"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_declare_controls_only_once() {
+        for control in Control::all() {
+            assert_eq!(Control::from_number(control.number()), control);
+            assert_eq!(Control::from_name(control.name().unwrap()), Some(control));
+        }
+    }
+}
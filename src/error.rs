@@ -0,0 +1,283 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::result;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// An error produced while communicating with FSUIPC
+#[derive(Debug)]
+pub enum Error {
+    /// FSUIPC is not running, so its window cannot be found
+    NotRunning,
+    /// The window message used to communicate with FSUIPC cannot be registered
+    RegisterMessageFailed,
+    /// The global atom used to identify the file mapping cannot be created
+    AtomCreationFailed,
+    /// The file mapping used to exchange data with FSUIPC cannot be created or mapped
+    MappingFailed,
+    /// FSUIPC did not respond in time
+    Timeout,
+    /// FSUIPC rejected the requests with the given code
+    Rejected { code: i64 },
+    /// An unexpected double word was found while reading a message header
+    /// The offset is relative to the beginning of the buffer the response is read from.
+    ProtocolViolation { offset_in_buffer: usize, found: u32 },
+    /// A message does not fit in the buffer it is read from
+    /// The message at `offset_in_buffer` requires `required` bytes, but only `available` bytes
+    /// are left in the buffer.
+    TruncatedMessage { offset_in_buffer: usize, required: usize, available: usize },
+    /// A field of a response message does not match the request it answers
+    /// The field at `offset_in_buffer` was requested as `expected`, but `found` is responded.
+    ResponseMismatch { offset_in_buffer: usize, expected: u32, found: u32 },
+    /// A request does not fit in the buffer used to exchange data with FSUIPC
+    /// The request requires `required` bytes, but only `available` bytes are left.
+    BufferOverflow { required: usize, available: usize },
+    /// A raw value read from an offset is not valid BCD for the requested conversion
+    InvalidBcd { raw: u16 },
+    /// A value cannot be encoded for an offset because it is out of the accepted range
+    OutOfRange { value: f64 },
+    /// A string does not fit in an offset of `max_len` bytes along with its null terminator
+    StringTooLong { len: usize, max_len: usize },
+    /// A string to be written contains non-ASCII characters
+    NonAsciiString,
+    /// An exchange does not match the next one of a recording being replayed
+    /// This is also returned when all the recorded exchanges were already replayed, or when the
+    /// recorded response does not have the length of the buffer.
+    ReplayMismatch { exchange: usize },
+    /// A control name does not match any of the known controls
+    UnknownControl { name: String },
+    /// A name of a Lua plugin or macro cannot be used in FSUIPC commands
+    /// Names must not be empty, and they cannot contain whitespaces nor colons.
+    InvalidCommandName { name: String },
+    /// A prepared session is executed against a handle whose buffers do not match the ones
+    /// it was prepared for
+    HandleMismatch,
+    /// An I/O error was produced
+    Io(io::Error),
+}
+
+/// The result of an operation that may fail with an `Error`
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// Move the offset of a protocol violation by the given number of bytes
+    pub(crate) fn relocated(self, pos: usize) -> Self {
+        match self {
+            Error::ProtocolViolation { offset_in_buffer, found } =>
+                Error::ProtocolViolation { offset_in_buffer: offset_in_buffer + pos, found },
+            other => other,
+        }
+    }
+
+    /// Encode the error into the given output
+    /// The error is encoded as a u8 kind and two u64 parameters. Errors that are not produced
+    /// by FSUIPC handles are encoded as an unknown kind. Errors with more than two parameters
+    /// pack two of them, which fit in 32 bits, into a single one.
+    pub(crate) fn write_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let (kind, a, b) = match *self {
+            Error::NotRunning => (0, 0, 0),
+            Error::RegisterMessageFailed => (1, 0, 0),
+            Error::AtomCreationFailed => (2, 0, 0),
+            Error::MappingFailed => (3, 0, 0),
+            Error::Timeout => (4, 0, 0),
+            Error::Rejected { code } => (5, code as u64, 0),
+            Error::ProtocolViolation { offset_in_buffer, found } =>
+                (6, offset_in_buffer as u64, found as u64),
+            Error::BufferOverflow { required, available } =>
+                (7, required as u64, available as u64),
+            Error::TruncatedMessage { offset_in_buffer, required, available } =>
+                (8, (offset_in_buffer as u64) << 32 | available as u64, required as u64),
+            Error::ResponseMismatch { offset_in_buffer, expected, found } =>
+                (9, offset_in_buffer as u64, (expected as u64) << 32 | found as u64),
+            _ => (255, 0, 0),
+        };
+        output.write_u8(kind)?;
+        output.write_u64::<LittleEndian>(a)?;
+        output.write_u64::<LittleEndian>(b)
+    }
+
+    /// Decode an error encoded by `write_to()` from the given input
+    pub(crate) fn read_from<R: Read>(input: &mut R) -> Result<Error> {
+        let kind = input.read_u8()?;
+        let a = input.read_u64::<LittleEndian>()?;
+        let b = input.read_u64::<LittleEndian>()?;
+        Ok(match kind {
+            0 => Error::NotRunning,
+            1 => Error::RegisterMessageFailed,
+            2 => Error::AtomCreationFailed,
+            3 => Error::MappingFailed,
+            4 => Error::Timeout,
+            5 => Error::Rejected { code: a as i64 },
+            6 => Error::ProtocolViolation { offset_in_buffer: a as usize, found: b as u32 },
+            7 => Error::BufferOverflow { required: a as usize, available: b as usize },
+            8 => Error::TruncatedMessage {
+                offset_in_buffer: (a >> 32) as usize,
+                required: b as usize,
+                available: a as u32 as usize,
+            },
+            9 => Error::ResponseMismatch {
+                offset_in_buffer: a as usize,
+                expected: (b >> 32) as u32,
+                found: b as u32,
+            },
+            _ => Error::Io(io::Error::other("unknown FSUIPC error")),
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotRunning =>
+                write!(f, "cannot connect to FSUIPC: cannot find FSUIPC window"),
+            Error::RegisterMessageFailed =>
+                write!(f, "cannot connect to FSUIPC: cannot register window message"),
+            Error::AtomCreationFailed =>
+                write!(f, "cannot connect to FSUIPC: cannot add global atom"),
+            Error::MappingFailed =>
+                write!(f, "cannot connect to FSUIPC: cannot create file mapping"),
+            Error::Timeout =>
+                write!(f, "timed out while waiting for a response from FSUIPC"),
+            Error::Rejected { code } =>
+                write!(f, "FSUIPC rejected the requests with error {}", code),
+            Error::ProtocolViolation { offset_in_buffer, found } =>
+                write!(f, "unexpected double word 0x{:x} at byte {} while reading IPC message header",
+                    found, offset_in_buffer),
+            Error::TruncatedMessage { offset_in_buffer, required, available } =>
+                write!(f, "IPC message at byte {} requires {} bytes, but only {} are left",
+                    offset_in_buffer, required, available),
+            Error::ResponseMismatch { offset_in_buffer, expected, found } =>
+                write!(f, "response has 0x{:x} at byte {} where 0x{:x} was requested",
+                    found, offset_in_buffer, expected),
+            Error::BufferOverflow { required, available } =>
+                write!(f, "request of {} bytes does not fit in the FSUIPC buffer ({} bytes left)",
+                    required, available),
+            Error::InvalidBcd { raw } =>
+                write!(f, "raw value 0x{:04x} is not valid BCD", raw),
+            Error::OutOfRange { value } =>
+                write!(f, "value {} is out of the range accepted by the offset", value),
+            Error::StringTooLong { len, max_len } =>
+                write!(f, "string of {} bytes does not fit in an offset of {} bytes",
+                    len, max_len),
+            Error::NonAsciiString =>
+                write!(f, "string contains non-ASCII characters"),
+            Error::ReplayMismatch { exchange } =>
+                write!(f, "exchange {} does not match the recording", exchange),
+            Error::UnknownControl { ref name } =>
+                write!(f, "unknown control '{}'", name),
+            Error::InvalidCommandName { ref name } =>
+                write!(f, "invalid name '{}' for a FSUIPC command", name),
+            Error::HandleMismatch =>
+                write!(f, "handle does not match the prepared session"),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self { Error::Io(e) }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match e {
+            Error::Io(e) => return e,
+            Error::NotRunning |
+            Error::RegisterMessageFailed |
+            Error::AtomCreationFailed |
+            Error::MappingFailed => io::ErrorKind::ConnectionRefused,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            Error::Rejected { .. } |
+            Error::ProtocolViolation { .. } |
+            Error::TruncatedMessage { .. } |
+            Error::ResponseMismatch { .. } |
+            Error::InvalidBcd { .. } |
+            Error::ReplayMismatch { .. } => io::ErrorKind::InvalidData,
+            Error::BufferOverflow { .. } |
+            Error::OutOfRange { .. } |
+            Error::StringTooLong { .. } |
+            Error::NonAsciiString |
+            Error::UnknownControl { .. } |
+            Error::InvalidCommandName { .. } |
+            Error::HandleMismatch => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn should_convert_into_io_error() {
+        let e: io::Error = Error::Timeout.into();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        let e: io::Error = Error::Rejected { code: 0 }.into();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "FSUIPC rejected the requests with error 0");
+    }
+
+    #[test]
+    fn should_encode_and_decode_errors() {
+        let mut bytes = Vec::new();
+        Error::Rejected { code: -1 }.write_to(&mut bytes).unwrap();
+        Error::BufferOverflow { required: 128, available: 64 }.write_to(&mut bytes).unwrap();
+        Error::TruncatedMessage { offset_in_buffer: 20, required: 76, available: 16 }
+            .write_to(&mut bytes).unwrap();
+        Error::ResponseMismatch { offset_in_buffer: 4, expected: 0x1000, found: 0x1002 }
+            .write_to(&mut bytes).unwrap();
+        Error::NonAsciiString.write_to(&mut bytes).unwrap();
+        let mut input = &bytes[..];
+        match Error::read_from(&mut input).unwrap() {
+            Error::Rejected { code: -1 } => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match Error::read_from(&mut input).unwrap() {
+            Error::BufferOverflow { required: 128, available: 64 } => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match Error::read_from(&mut input).unwrap() {
+            Error::TruncatedMessage { offset_in_buffer: 20, required: 76, available: 16 } => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match Error::read_from(&mut input).unwrap() {
+            Error::ResponseMismatch { offset_in_buffer: 4, expected: 0x1000, found: 0x1002 } => {},
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match Error::read_from(&mut input).unwrap() {
+            Error::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::Other),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_unwrap_io_error() {
+        let original = io::Error::new(io::ErrorKind::UnexpectedEof, "eof");
+        let e: io::Error = Error::from(original).into();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(e.to_string(), "eof");
+    }
+}
@@ -9,9 +9,12 @@
 
 use std::io;
 use std::io::{Read, Write};
+use std::ops::Range;
+use std::ptr;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use super::{Error, Result};
 use super::raw::RawBytes;
 
 #[cfg(all(windows, target_pointer_width = "32"))]
@@ -40,75 +43,99 @@ pub enum MsgHeader {
         offset: u16,
         len: usize,
     },
+    /// Termination mark
+    /// It indicates there are no more messages in the buffer.
     TerminationMark
 }
 
+impl MsgHeader {
+    /// The number of bytes of the header once encoded
+    pub fn header_len(&self) -> usize {
+        match *self {
+            MsgHeader::ReadStateData { .. } => RSD_HEADER_LEN,
+            MsgHeader::WriteStateData { .. } => WSD_HEADER_LEN,
+            MsgHeader::TerminationMark => TM_HEADER_LEN,
+        }
+    }
+
+    /// The number of bytes of the body that follows the header
+    pub fn body_len(&self) -> usize {
+        match *self {
+            MsgHeader::ReadStateData { len, .. } => len,
+            MsgHeader::WriteStateData { len, .. } => len,
+            MsgHeader::TerminationMark => 0,
+        }
+    }
+}
+
+/// An object able to read IPC messages
+/// This trait is implemented for any `Read` object.
 pub trait MsgRead : Read {
     /// Read a IPC message header from the given `Read` object.
-    /// It returns the read message header and the number of bytes processed.
-    fn read_header(&mut self) -> io::Result<MsgHeader> {
+    /// If an unexpected double word is found, it returns `Error::ProtocolViolation` with an
+    /// offset relative to the beginning of the header.
+    fn read_header(&mut self) -> Result<MsgHeader> {
         match self.read_u32::<LittleEndian>()? {
             FS6IPC_READSTATEDATA_ID => {
                 let offset = self.read_u32::<LittleEndian>()? as u16;
                 let len = self.read_u32::<LittleEndian>()? as usize;
                 let target = self.read_u32::<LittleEndian>()? as *mut u8;
                 Ok(MsgHeader::ReadStateData {
-                    offset: offset,
-                    len: len,
-                    target: target,
+                    offset,
+                    len,
+                    target,
                 })
             },
             FS6IPC_WRITESTATEDATA_ID => {
                 let offset = self.read_u32::<LittleEndian>()? as u16;
                 let len = self.read_u32::<LittleEndian>()? as usize;
                 Ok(MsgHeader::WriteStateData {
-                    offset: offset,
-                    len: len,
+                    offset,
+                    len,
                 })
             },
-            FS6IPC_TERMINATIONMARK_ID => return Ok(MsgHeader::TerminationMark),
-            unexpected => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("unexpected double word 0x{} while reading IPC message header",
-                    unexpected))),
+            FS6IPC_TERMINATIONMARK_ID => Ok(MsgHeader::TerminationMark),
+            unexpected => Err(Error::ProtocolViolation { offset_in_buffer: 0, found: unexpected }),
         }
     }
 
     fn read_body<W: Write>(&mut self, header: &MsgHeader, output: &mut W) -> io::Result<usize> {
-        match header {
-            &MsgHeader::ReadStateData { offset: _, len, target: _ } => {
+        match *header {
+            MsgHeader::ReadStateData { offset: _, len, target: _ } => {
                 for _ in 0..len { output.write_u8(self.read_u8()?)?; }
                 Ok(len)
             },
-            &MsgHeader::WriteStateData { offset: _, len } => {
+            MsgHeader::WriteStateData { offset: _, len } => {
                 for _ in 0..len { output.write_u8(self.read_u8()?)?; }
                 Ok(len)
             },
-            &MsgHeader::TerminationMark => Ok(0),
+            MsgHeader::TerminationMark => Ok(0),
         }
     }
 }
 
 impl<R: Read + ?Sized> MsgRead for R {}
 
+/// An object able to write IPC messages
+/// This trait is implemented for any `Write` object.
 pub trait MsgWrite : Write {
     /// Write a IPC message header into the given `Write` object.
     fn write_header(&mut self, msg: &MsgHeader) -> io::Result<usize> {
-        match msg {
-            &MsgHeader::ReadStateData { offset, len, target } => {
+        match *msg {
+            MsgHeader::ReadStateData { offset, len, target } => {
                 self.write_u32::<LittleEndian>(FS6IPC_READSTATEDATA_ID)?;
                 self.write_u32::<LittleEndian>(offset as u32)?;
                 self.write_u32::<LittleEndian>(len as u32)?;
                 self.write_u32::<LittleEndian>(target as u32)?;
                 Ok(16)
             },
-            &MsgHeader::WriteStateData { offset, len } => {
+            MsgHeader::WriteStateData { offset, len } => {
                 self.write_u32::<LittleEndian>(FS6IPC_WRITESTATEDATA_ID)?;
                 self.write_u32::<LittleEndian>(offset as u32)?;
                 self.write_u32::<LittleEndian>(len as u32)?;
                 Ok(12)
             },
-            &MsgHeader::TerminationMark => {
+            MsgHeader::TerminationMark => {
                 self.write_u32::<LittleEndian>(FS6IPC_TERMINATIONMARK_ID)?;
                 Ok(4)
             },
@@ -116,22 +143,22 @@ pub trait MsgWrite : Write {
     }
 
     fn write_body<R: Read>(&mut self, header: &MsgHeader, input: &mut R) -> io::Result<usize> {
-        match header {
-            &MsgHeader::ReadStateData { offset: _, len, target: _ } => {
+        match *header {
+            MsgHeader::ReadStateData { offset: _, len, target: _ } => {
                 for _ in 0..len { self.write_u8(input.read_u8()?)?; }
                 Ok(len)
             },
-            &MsgHeader::WriteStateData { offset: _, len } => {
+            MsgHeader::WriteStateData { offset: _, len } => {
                 for _ in 0..len { self.write_u8(input.read_u8()?)?; }
                 Ok(len)
             },
-            &MsgHeader::TerminationMark => Ok(0),
+            MsgHeader::TerminationMark => Ok(0),
         }
     }
 
     fn write_rsd(&mut self, offset: u16, dest: *mut u8, len: usize) -> io::Result<usize> {
         let header = MsgHeader::ReadStateData {
-            offset: offset, len: len, target: dest,
+            offset, len, target: dest,
         };
         let hdr_bytes = self.write_header(&header)?;
         let body_bytes = self.write_body(&header, &mut io::repeat(0))?;
@@ -140,7 +167,7 @@ pub trait MsgWrite : Write {
 
     fn write_wsd(&mut self, offset: u16, src: *const u8, len: usize) -> io::Result<usize> {
         let header = MsgHeader::WriteStateData {
-            offset: offset, len: len,
+            offset, len,
         };
         let hdr_bytes = self.write_header(&header)?;
         let body_bytes = self.write_body(&header, &mut RawBytes::new(src, len))?;
@@ -150,6 +177,188 @@ pub trait MsgWrite : Write {
 
 impl<W: Write + ?Sized> MsgWrite for W {}
 
+/// A table of destinations for the data read from FSUIPC
+/// Read requests carry a 32-bit target that FSUIPC sends back along with the data. Instead of the
+/// address of the destination, which does not fit in the target in 64-bit platforms, the target
+/// carries the index of the destination in this table.
+pub(crate) struct Destinations {
+    targets: Vec<Target>,
+    parts: Vec<Part>,
+}
+
+/// A target of the read requests, with the offset and length requested for it
+/// The headers of the response are checked against them, so a response that does not match
+/// the request never writes out of the destinations.
+struct Target {
+    offset: u16,
+    len: usize,
+    dest: Dest,
+}
+
+/// The destination of the data read for a target
+enum Dest {
+    /// All the data is copied into a single destination
+    Single(*mut u8),
+    /// The data is scattered into the parts in the given range
+    Scatter(Range<usize>),
+}
+
+/// A part of the data read to be copied into a destination
+/// The part comprises `len` bytes after skipping the first `skip` bytes of the data.
+pub(crate) struct Part {
+    pub dest: *mut u8,
+    pub skip: usize,
+    pub len: usize,
+}
+
+impl Destinations {
+    pub fn new() -> Self {
+        Destinations { targets: Vec::new(), parts: Vec::new() }
+    }
+
+    /// Register a new destination for `len` bytes read from `offset`, returning the target to
+    /// be used in the read request.
+    pub fn register(&mut self, offset: u16, len: usize, dest: *mut u8) -> *mut u8 {
+        self.push(offset, len, Dest::Single(dest))
+    }
+
+    /// Register a set of destinations for parts of the `len` bytes read from `offset`,
+    /// returning the target to be used in the read request. The parts must be within the data.
+    pub fn register_parts<I>(&mut self, offset: u16, len: usize, parts: I) -> *mut u8
+        where I: IntoIterator<Item=Part>
+    {
+        let begin = self.parts.len();
+        self.parts.extend(parts);
+        let end = self.parts.len();
+        debug_assert!(self.parts[begin..end].iter().all(|p| p.skip + p.len <= len));
+        self.push(offset, len, Dest::Scatter(begin..end))
+    }
+
+    fn push(&mut self, offset: u16, len: usize, dest: Dest) -> *mut u8 {
+        let idx = self.targets.len();
+        self.targets.push(Target { offset, len, dest });
+        idx as *mut u8
+    }
+
+    /// Read the response messages until termination mark, copying the data into destinations.
+    /// The whole response is checked before copying anything, and no destination is written if
+    /// it fails. A response must have the read messages of the request in the same order,
+    /// failing with `Error::ResponseMismatch` if a field differs from the request, and with
+    /// `Error::ProtocolViolation` if a read message is missing or not requested. Messages not
+    /// fitting in the response fail with `Error::TruncatedMessage`. The offsets of the errors
+    /// are relative to the beginning of `response`.
+    ///
+    /// This is unsafe since the data is copied into the raw destinations passed to `register()`.
+    pub unsafe fn read_response(&self, response: &[u8]) -> Result<()> {
+        for (target, body) in self.check_response(response)? {
+            match target.dest {
+                Dest::Single(dest) => {
+                    ptr::copy_nonoverlapping(body.as_ptr(), dest, body.len());
+                },
+                Dest::Scatter(ref range) => {
+                    for part in &self.parts[range.clone()] {
+                        let src = &body[part.skip..part.skip + part.len];
+                        ptr::copy_nonoverlapping(src.as_ptr(), part.dest, part.len);
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the messages of the response, returning the body read for each target
+    fn check_response<'r>(&self, response: &'r [u8]) -> Result<Vec<(&Target, &'r [u8])>> {
+        let mut start = 0;
+        let mut bodies = Vec::new();
+        loop {
+            let (header, body) = read_message(response, start)?;
+            let index = bodies.len();
+            match header {
+                MsgHeader::ReadStateData { offset, len, target } => {
+                    let expected = self.targets.get(index).ok_or(Error::ProtocolViolation {
+                        offset_in_buffer: start, found: FS6IPC_READSTATEDATA_ID })?;
+                    let mismatch = |pos, expected, found| Error::ResponseMismatch {
+                        offset_in_buffer: start + pos, expected, found };
+                    if target as usize != index {
+                        return Err(mismatch(12, index as u32, target as u32));
+                    }
+                    if offset != expected.offset {
+                        return Err(mismatch(4, expected.offset as u32, offset as u32));
+                    }
+                    if len != expected.len {
+                        return Err(mismatch(8, expected.len as u32, len as u32));
+                    }
+                    bodies.push((expected, &response[body.clone()]));
+                },
+                MsgHeader::WriteStateData { .. } => {},
+                MsgHeader::TerminationMark if index < self.targets.len() =>
+                    return Err(Error::ProtocolViolation {
+                        offset_in_buffer: start, found: FS6IPC_TERMINATIONMARK_ID }),
+                MsgHeader::TerminationMark => return Ok(bodies),
+            }
+            start = body.end;
+        }
+    }
+}
+
+/// Read the header of the message at `start` of the buffer, along with the range of its body
+/// It fails with `Error::TruncatedMessage` if the message does not fit in the buffer, and with
+/// `Error::ProtocolViolation` if the header is not valid.
+pub(crate) fn read_message(buffer: &[u8], start: usize) -> Result<(MsgHeader, Range<usize>)> {
+    let mut input = buffer.get(start..).unwrap_or(&[]);
+    let available = input.len();
+    let truncated = |required| Error::TruncatedMessage {
+        offset_in_buffer: start, required, available };
+    let header = match input.read_header() {
+        Ok(header) => header,
+        Err(Error::Io(_)) => {
+            let required = match (&buffer[start..]).read_u32::<LittleEndian>() {
+                Ok(FS6IPC_READSTATEDATA_ID) => RSD_HEADER_LEN,
+                Ok(_) => WSD_HEADER_LEN,
+                Err(_) => 4,
+            };
+            return Err(truncated(required));
+        },
+        Err(e) => return Err(e.relocated(start)),
+    };
+    let required = header.header_len().saturating_add(header.body_len());
+    if required > available {
+        return Err(truncated(required));
+    }
+    let body = start + header.header_len()..start + required;
+    Ok((header, body))
+}
+
+/// Check the given buffer is a well-formed request, without the prefix of the handle
+/// The messages must be followed by the termination mark, and they must fit in the buffer.
+/// Otherwise, it fails with `Error::TruncatedMessage` or `Error::ProtocolViolation`.
+pub(crate) fn check_request(buffer: &[u8]) -> Result<()> {
+    let mut start = 0;
+    loop {
+        match read_message(buffer, start)? {
+            (MsgHeader::TerminationMark, _) => return Ok(()),
+            (_, body) => start = body.end,
+        }
+    }
+}
+
+/// Check there is room for a message of `required` bytes in a buffer with `available` bytes
+/// Room for the termination mark is always reserved, so the buffer can be terminated afterwards.
+pub(crate) fn ensure_room(required: usize, available: usize) -> Result<()> {
+    let available = available.saturating_sub(TM_HEADER_LEN);
+    if required > available {
+        return Err(Error::BufferOverflow { required, available });
+    }
+    Ok(())
+}
+
+/// The maximum length of the buffers exchanged with FSUIPC
+pub(crate) const MAX_BUFFER_LEN: usize = 64*1024;
+
+pub(crate) const RSD_HEADER_LEN: usize = 16;
+pub(crate) const WSD_HEADER_LEN: usize = 12;
+pub(crate) const TM_HEADER_LEN: usize = 4;
+
 const FS6IPC_TERMINATIONMARK_ID: u32 = 0;
 const FS6IPC_READSTATEDATA_ID: u32 = 1;
 const FS6IPC_WRITESTATEDATA_ID: u32 = 2;
@@ -157,7 +366,7 @@ const FS6IPC_WRITESTATEDATA_ID: u32 = 2;
 #[cfg(test)]
 mod test {
 
-    use std::io::{Cursor, ErrorKind};
+    use std::io::Cursor;
 
     use byteorder::{LittleEndian, ReadBytesExt};
 
@@ -241,9 +450,10 @@ mod test {
     #[test]
     fn should_fail_to_read_from_invalid_stream() {
         let mut buff: &[u8] = &[0x01, 0x02, 0x03, 0x04];
-        let expected_error = ErrorKind::InvalidData;
-        let actual_error = buff.read_header().err().unwrap().kind();
-        assert_eq!(actual_error, expected_error);
+        match buff.read_header() {
+            Err(Error::ProtocolViolation { offset_in_buffer: 0, found: 0x04030201 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
     }
 
     #[test]
@@ -352,4 +562,119 @@ mod test {
         assert_eq!(buff.write_body(&MsgHeader::TerminationMark, &mut input).unwrap(), 0);
         assert_eq!(buff.len(), 0);
     }
+
+    fn response(offset: u16, len: usize, target: *mut u8, body: &[u8]) -> Vec<u8> {
+        let mut buff = Vec::new();
+        buff.write_header(&MsgHeader::ReadStateData { offset, len, target }).unwrap();
+        buff.extend_from_slice(body);
+        buff.write_header(&MsgHeader::TerminationMark).unwrap();
+        buff
+    }
+
+    #[test]
+    fn should_copy_response_into_destinations() {
+        let mut value = [0u8; 4];
+        let mut parts = [0u8; 3];
+        let mut dests = Destinations::new();
+        let single = dests.register(0x1000, 4, value.as_mut_ptr());
+        let scatter = dests.register_parts(0x2000, 4, vec![
+            Part { dest: parts.as_mut_ptr(), skip: 0, len: 1 },
+            Part { dest: parts[1..].as_mut_ptr(), skip: 2, len: 2 },
+        ]);
+        let mut buff = response(0x1000, 4, single, &[1, 2, 3, 4]);
+        buff.truncate(buff.len() - TM_HEADER_LEN);
+        buff.extend(response(0x2000, 4, scatter, &[5, 6, 7, 8]));
+        unsafe { dests.read_response(&buff).unwrap() };
+        assert_eq!(value, [1, 2, 3, 4]);
+        assert_eq!(parts, [5, 7, 8]);
+    }
+
+    #[test]
+    fn should_reject_responses_not_matching_the_request() {
+        let mut value = [0u8; 2];
+        let mut dests = Destinations::new();
+        let target = dests.register(0x1000, 2, value.as_mut_ptr());
+        let cases = vec![
+            (response(0x1000, 8, target, &[9; 8]), 8, 2, 8),
+            (response(0x1002, 2, target, &[9; 2]), 4, 0x1000, 0x1002),
+            (response(0x1000, 2, 5 as *mut u8, &[9; 2]), 12, 0, 5),
+        ];
+        for (buff, offset, requested, value) in cases {
+            match unsafe { dests.read_response(&buff) } {
+                Err(Error::ResponseMismatch { offset_in_buffer, expected, found }) => {
+                    assert_eq!(offset_in_buffer, offset);
+                    assert_eq!(expected, requested);
+                    assert_eq!(found, value);
+                },
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(value, [0, 0]);
+    }
+
+    #[test]
+    fn should_reject_responses_with_missing_or_extra_reads() {
+        let mut value = [0u8; 2];
+        let mut dests = Destinations::new();
+        let target = dests.register(0x1000, 2, value.as_mut_ptr());
+        let mut buff = Vec::new();
+        buff.write_header(&MsgHeader::TerminationMark).unwrap();
+        match unsafe { dests.read_response(&buff) } {
+            Err(Error::ProtocolViolation { offset_in_buffer: 0, found: 0 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let mut buff = response(0x1000, 2, target, &[1, 2]);
+        buff.truncate(buff.len() - TM_HEADER_LEN);
+        buff.extend(response(0x1000, 2, target, &[3, 4]));
+        match unsafe { dests.read_response(&buff) } {
+            Err(Error::ProtocolViolation { offset_in_buffer: 18, found: 1 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(value, [0, 0]);
+    }
+
+    #[test]
+    fn should_reject_truncated_messages() {
+        let mut value = [0u8; 2];
+        let mut dests = Destinations::new();
+        let target = dests.register(0x1000, 2, value.as_mut_ptr());
+        let buff = response(0x1000, 2, target, &[])[..16].to_vec();
+        match unsafe { dests.read_response(&buff) } {
+            Err(Error::TruncatedMessage { offset_in_buffer: 0, required: 18, available: 16 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let cases = vec![
+            (vec![1, 0, 0, 0], RSD_HEADER_LEN),
+            (vec![2, 0, 0, 0], WSD_HEADER_LEN),
+            (vec![2, 0], 4),
+        ];
+        for (buff, header_len) in cases {
+            match check_request(&buff) {
+                Err(Error::TruncatedMessage { offset_in_buffer: 0, required, available }) => {
+                    assert_eq!(required, header_len);
+                    assert_eq!(available, buff.len());
+                },
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(value, [0, 0]);
+    }
+
+    #[test]
+    fn should_not_copy_anything_before_rejecting_a_response() {
+        let mut first = [0u8; 2];
+        let mut second = [0u8; 2];
+        let mut dests = Destinations::new();
+        let t1 = dests.register(0x1000, 2, first.as_mut_ptr());
+        let t2 = dests.register(0x2000, 2, second.as_mut_ptr());
+        let mut buff = response(0x1000, 2, t1, &[1, 2]);
+        buff.truncate(buff.len() - TM_HEADER_LEN);
+        buff.extend(response(0x2000, 4, t2, &[3, 4, 5, 6]));
+        match unsafe { dests.read_response(&buff) } {
+            Err(Error::ResponseMismatch { offset_in_buffer: 26, expected: 2, found: 4 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(first, [0, 0]);
+        assert_eq!(second, [0, 0]);
+    }
 }
@@ -8,21 +8,60 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 extern crate byteorder;
+#[cfg(windows)]
 extern crate kernel32;
+#[cfg(windows)]
 extern crate user32;
+#[cfg(windows)]
 extern crate winapi;
 
-mod ipc;
+mod batch;
+mod error;
 mod raw;
+mod string;
 
-#[cfg(all(windows))]
+pub mod bridge;
+pub mod command;
+pub mod controls;
+pub mod ipc;
+pub mod message;
+pub mod mock;
+pub mod offsets;
+pub mod prepared;
+pub mod reconnect;
+pub mod recording;
+pub mod safe;
+pub mod server;
+pub mod shared;
+pub mod wide;
+
+#[macro_use]
+mod value;
+
+pub mod bcd;
+pub mod units;
+pub mod watcher;
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+
+#[cfg(windows)]
 pub mod local;
 
-#[cfg(all(windows))]
+#[cfg(windows)]
 pub mod user;
 
-use std::io;
 use std::mem::size_of;
+use std::time::Duration;
+
+use command::LuaCommand;
+use message::MessageMode;
+use safe::SafeSession;
+
+pub use batch::{BatchSession, HandleRef};
+pub use error::{Error, Result};
+pub use offsets::Offset;
+pub use value::{Decoder, OffsetValue};
 
 /// A handle to FSUIPC
 /// This type represents a handle to FSUIPC. It cannot be used directly to read of write from or
@@ -33,6 +72,28 @@ pub trait Handle<'a> {
 
     /// Create a new session from this handle
     fn session(&'a mut self) -> Self::Sess;
+
+    /// Create a new safe session from this handle
+    /// Safe sessions do not require raw pointers or references to the read destinations.
+    /// Check `SafeSession` for further details.
+    fn safe_session(&'a mut self) -> SafeSession<Self::Sess> {
+        SafeSession::new(self.session())
+    }
+}
+
+/// A channel to exchange raw IPC buffers with FSUIPC
+/// Buffers contain a sequence of IPC messages (see `ipc` module) ended with a termination mark,
+/// preceded by `prefix_len()` bytes reserved for the implementation. FSUIPC processes the
+/// messages and leaves the response in the same buffer.
+pub trait Exchange {
+    /// The number of bytes reserved at the beginning of each buffer
+    fn prefix_len(&self) -> usize { 0 }
+
+    /// The maximum number of bytes of messages in a buffer, not including the prefix
+    fn buffer_len(&self) -> usize;
+
+    /// Exchange the given buffer with FSUIPC, leaving the response in place
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()>;
 }
 
 /// A session of read & write operations from/to FSUIPC
@@ -41,15 +102,135 @@ pub trait Handle<'a> {
 /// They are not executed immediately but after calling `process()` method, which consumes
 /// the session.
 pub trait Session {
-    fn read_bytes(&mut self, offset: u16, dest: *mut u8, len: usize) -> io::Result<usize>;
-    fn write_bytes(&mut self, offset: u16, src: *const u8, len: usize) -> io::Result<usize>;
-    fn process(self) -> io::Result<usize>;
+    /// Request to read `len` bytes from the given offset into `dest`
+    /// The destination must remain valid until the session is processed.
+    fn read_bytes(&mut self, offset: u16, dest: *mut u8, len: usize) -> Result<usize>;
+
+    /// Request to read `len` bytes from the given offset into `dest`, decoding them in place
+    /// The bytes are decoded by `decode` once they are copied into `dest`. The destination
+    /// must remain valid until the session is processed.
+    fn read_decoded(&mut self, offset: u16, dest: *mut u8, len: usize, decode: Decoder)
+        -> Result<usize>;
+
+    /// Request to write `len` bytes from `src` into the given offset
+    /// The bytes are copied from `src` before returning, so it does not need to outlive the
+    /// session.
+    fn write_bytes(&mut self, offset: u16, src: *const u8, len: usize) -> Result<usize>;
+
+    /// Process the requested operations
+    fn process(self) -> Result<usize>;
+
+    /// Request to read from the given offset into `result`
+    /// The length of the offset is inferred from the type `T`, and the value is decoded from
+    /// the little-endian bytes of the offset with `OffsetValue::decode_le()`.
+    fn read<'a, T: OffsetValue>(&'a mut self, offset: u16, result: &'a mut T)
+        -> Result<usize>
+    {
+        let decode: Decoder = value::decode_in_place::<T>;
+        self.read_decoded(offset, result as *mut T as *mut u8, size_of::<T>(), decode)
+    }
+
+    /// Request to write the given value into the given offset
+    /// The length of the offset is inferred from the type `T`.
+    fn write<T: OffsetValue>(&mut self, offset: u16, value: &T) -> Result<usize> {
+        let mut bytes = vec![0; size_of::<T>()];
+        value.encode_le(&mut bytes);
+        self.write_bytes(offset, bytes.as_ptr(), bytes.len())
+    }
+
+    /// Request to write the given ASCII string into an offset of `max_len` bytes
+    /// The string is padded with nulls up to `max_len`, and it must leave room for the null
+    /// terminator. Otherwise, `Error::StringTooLong` is returned.
+    fn write_string(&mut self, offset: u16, max_len: usize, value: &str) -> Result<usize> {
+        let bytes = string::encode(value, max_len)?;
+        self.write_bytes(offset, bytes.as_ptr(), bytes.len())
+    }
+
+    /// Request to read from the given typed offset into `result`
+    /// Check the `offsets` module for the commonly used offsets.
+    fn read_offset<'a, T: OffsetValue>(&'a mut self, offset: Offset<T>, result: &'a mut T)
+        -> Result<usize>
+    {
+        self.read(offset.address(), result)
+    }
+
+    /// Request to write the given value into the given typed offset
+    fn write_offset<T: OffsetValue>(&mut self, offset: Offset<T>, value: &T) -> Result<usize> {
+        self.write(offset.address(), value)
+    }
+
+    /// Request to send the given control to the simulator with the given parameter
+    /// The control and its parameter are written together, so the control is sent along with
+    /// its own parameter even if several controls are requested in the same session. The
+    /// parameter must fit in 32 bits, either signed or unsigned. Otherwise,
+    /// `Error::OutOfRange` is returned.
+    fn send_control(&mut self, control: u32, param: i64) -> Result<usize> {
+        if param < i64::from(i32::MIN) || param > i64::from(u32::MAX) {
+            return Err(Error::OutOfRange { value: param as f64 });
+        }
+        let mut bytes = [0u8; 8];
+        bytes[..4].copy_from_slice(&control.to_le_bytes());
+        bytes[4..].copy_from_slice(&(param as u32).to_le_bytes());
+        self.write_bytes(offsets::CONTROL.address(), bytes.as_ptr(), bytes.len())
+    }
 
-    fn read<'a, T>(&'a mut self, offset: u16, result: &'a mut T) -> io::Result<usize> {
-        self.read_bytes(offset, result as *mut T as *mut u8, size_of::<T>())
+    /// Request to display the given message in the simulator window
+    /// The message must be ASCII and shorter than 128 bytes, leaving room for the null
+    /// terminator. Check `MessageMode` for the ways it can be displayed.
+    fn display_message(&mut self, text: &str, duration: Duration, mode: MessageMode)
+        -> Result<usize>
+    {
+        let control = message::encode_control(duration, mode)?;
+        let text_bytes = self.write_string(
+            offsets::MESSAGE_TEXT.address(), message::MAX_MESSAGE_LEN, text)?;
+        let control_bytes = self.write_offset(offsets::MESSAGE_CONTROL, &control)?;
+        Ok(text_bytes + control_bytes)
     }
 
-    fn write<T>(&mut self, offset: u16, value: &T) -> io::Result<usize> {
-        self.write_bytes(offset, value as *const T as *const u8, size_of::<T>())
+    /// Request to clear the message displayed in the simulator window
+    fn clear_message(&mut self) -> Result<usize> {
+        let text_bytes = self.write(offsets::MESSAGE_TEXT.address(), &0u8)?;
+        let control_bytes = self.write_offset(offsets::MESSAGE_CONTROL, &0)?;
+        Ok(text_bytes + control_bytes)
+    }
+
+    /// Request to send the given command to the Lua plugin with the given name
+    /// The name is the one of the plugin file without the `.lua` extension. Check
+    /// `LuaCommand` for the commands that can be sent.
+    fn lua(&mut self, name: &str, command: LuaCommand) -> Result<usize> {
+        let bytes = command::encode_lua(name, command)?;
+        self.write_bytes(offsets::COMMAND_PARAMETER.address(), bytes.as_ptr(), bytes.len())
+    }
+
+    /// Request to execute the given control of a macro file with the given parameter
+    /// The file is the name of the macro file without the `.mcro` extension.
+    fn execute_macro(&mut self, file: &str, control: &str, param: i32) -> Result<usize> {
+        let bytes = command::encode_macro(file, control, param)?;
+        self.write_bytes(offsets::COMMAND_PARAMETER.address(), bytes.as_ptr(), bytes.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::mock::MockHandle;
+
+    #[test]
+    fn should_send_controls_with_their_parameters() {
+        let mut handle = MockHandle::new();
+        let nbytes = {
+            let mut session = handle.session();
+            session.send_control(65580, 0).unwrap();
+            session.send_control(66390, -1).unwrap();
+            match session.send_control(65580, 1 << 32) {
+                Err(Error::OutOfRange { .. }) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+            session.process().unwrap()
+        };
+        assert_eq!(nbytes, 2 * (12 + 8) + 4);
+        assert_eq!(handle.get::<u32>(0x3110), 66390);
+        assert_eq!(handle.get::<i32>(0x3114), -1);
     }
 }
@@ -8,7 +8,6 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::ffi::CString;
-use std::io;
 use std::ptr;
 
 use user32::{FindWindowExA, SendMessageTimeoutA};
@@ -16,9 +15,8 @@ use winapi::WM_USER;
 use winapi::windef::HWND;
 use winapi::winuser::SMTO_BLOCK;
 
-use super::{Handle, Session};
+use super::{BatchSession, Error, Exchange, Handle, HandleRef, Result};
 use super::ipc::*;
-use super::raw::MutRawBytes;
 
 /// A handle to FSUIPc that uses local IPC communication to the FSUIPC module
 /// This kind of handle must be used from code running in the same process as FSUIPC does.
@@ -30,123 +28,65 @@ pub struct LocalHandle {
 unsafe impl Send for LocalHandle {}
 
 impl LocalHandle {
-    pub fn new() -> io::Result<Self> {
+    pub fn new() -> Result<Self> {
         unsafe {
             let win_name = CString::new("UIPCMAIN").unwrap();
             let handle = FindWindowExA(
                 ptr::null_mut(), ptr::null_mut(), win_name.as_ptr(), ptr::null_mut());
-            if handle != ptr::null_mut() {
-                Ok(LocalHandle { handle: handle })
+            if !handle.is_null() {
+                Ok(LocalHandle { handle })
             } else {
-                Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    "cannot connect to local FSUIPC: cannot create window handle"))
+                Err(Error::NotRunning)
             }
         }
     }
 }
 
-impl<'a> Handle<'a> for LocalHandle {
-    type Sess = LocalSession;
-
-    fn session(&'a mut self) -> LocalSession {
-        LocalSession::new(self.handle)
-    }
-}
+impl Exchange for LocalHandle {
+    // First 4-bytes seems to be for a stack frame pointer that is not actually used
+    fn prefix_len(&self) -> usize { 4 }
 
-pub struct LocalSession {
-    handle: HWND,
-    buffer: io::Cursor<Vec<u8>>,
-    #[cfg(target_pointer_width = "64")]
-    destinations: Vec<*mut u8>,
-}
-
-impl LocalSession {
-    fn new(handle: HWND) -> Self {
-        let mut session = LocalSession {
-            handle: handle,
-            buffer: io::Cursor::new(Vec::with_capacity(4096)),
-            #[cfg(target_pointer_width = "64")]
-            destinations: Vec::new(),
-        };
-        // First 4-bytes seems to be for a stack frame pointer that is not actually used
-        session.buffer.set_position(4);
-        session
-    }
-}
-
-impl Session for LocalSession {
-    fn read_bytes(&mut self, offset: u16, dest: *mut u8, len: usize) -> io::Result<usize> {
-        #[cfg(target_pointer_width = "64")]
-        {
-            let idx = self.destinations.len();
-            self.destinations.push(dest);
-            self.buffer.write_rsd(offset, idx as *mut u8, len)
-        }
-        #[cfg(not(target_pointer_width = "64"))]
-        {
-            self.buffer.write_rsd(offset, dest, len)
-        }
-    }
+    fn buffer_len(&self) -> usize { MAX_BUFFER_LEN }
 
-    fn write_bytes(&mut self, offset: u16, src: *const u8, len: usize) -> io::Result<usize> {
-        self.buffer.write_wsd(offset, src, len)
-    }
-
-    fn process(mut self) -> io::Result<usize> {
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
         unsafe {
-            self.buffer.write_header(&MsgHeader::TerminationMark)?;
-            let nbytes = self.buffer.position() as usize;
-            let buff = self.buffer.get_ref().as_ptr() as WinInt;
             let mut process_result: WinUInt = 0;
             let send_result = SendMessageTimeoutA(
                 self.handle,
                 WM_IPCTHREADACCESS,
-                nbytes as WinUInt,
-                buff,
+                buffer.len() as WinUInt,
+                buffer.as_mut_ptr() as WinInt,
                 SMTO_BLOCK,
                 WM_IPC_TIMEOUT,
                 &mut process_result as *mut WinUInt);
             if send_result == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::TimedOut,
-                    "timed out while waiting for a response from FSUIPC"));
+                return Err(Error::Timeout);
             }
             if process_result != FS6IPC_MESSAGE_SUCCESS {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
-                    "FSUIPC rejected the requests with error {}; possible buffer corruption in bytes: {:?}",
-                    send_result, self.buffer.get_ref())));
-            }
-            // First 4-bytes seems to be for a stack frame pointer that is not actually used
-            self.buffer.set_position(4);
-            loop {
-                let header = self.buffer.read_header()?;
-                match &header {
-                    &MsgHeader::ReadStateData { offset: _, len, target } => {
-                        #[cfg(target_pointer_width = "64")]
-                        let actual = {
-                            let idx = target as usize;
-                            *self.destinations.get(idx).ok_or_else(|| {
-                                io::Error::new(io::ErrorKind::InvalidData, "invalid destination index")
-                            })?
-                        };
-                        #[cfg(target_pointer_width = "64")]
-                        let mut output = MutRawBytes::new(actual, len);
-                        #[cfg(not(target_pointer_width = "64"))]
-                        let mut output = MutRawBytes::new(target, len);
-                        self.buffer.read_body(&header, &mut output)?;
-                    },
-                    &MsgHeader::WriteStateData { offset: _, len: _ } => {
-                        let mut output = io::sink();
-                        self.buffer.read_body(&header, &mut output)?;
-                    },
-                    &MsgHeader::TerminationMark => return Ok(nbytes),
-                }
+                return Err(Error::Rejected { code: process_result as i64 });
             }
+            Ok(())
         }
     }
 }
 
+impl<'a> Handle<'a> for LocalHandle {
+    type Sess = LocalSession;
+
+    fn session(&'a mut self) -> LocalSession {
+        BatchSession::new(self.clone(), MAX_BUFFER_LEN)
+    }
+}
+
+/// A session of a `LocalHandle`
+pub type LocalSession = BatchSession<LocalHandle>;
+
+impl HandleRef for LocalHandle {
+    type Target = LocalHandle;
+
+    fn with_handle<R, F: FnOnce(&mut LocalHandle) -> R>(&mut self, f: F) -> R { f(self) }
+}
+
 const FS6IPC_MESSAGE_SUCCESS: WinUInt = 1;
 const WM_IPCTHREADACCESS: u32 = WM_USER + 130;
 const WM_IPC_TIMEOUT: u32 = 10000;
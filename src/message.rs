@@ -0,0 +1,113 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::time::Duration;
+
+use super::{Error, Result};
+
+/// The maximum length of a message displayed in the simulator, including the null terminator
+pub const MAX_MESSAGE_LEN: usize = 128;
+
+/// The way a message is displayed in the simulator window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageMode {
+    /// The message is displayed during the given duration
+    Timed,
+    /// The message is displayed until it is replaced or cleared, ignoring the duration
+    Persistent,
+    /// The message scrolls along the window during the given duration, at least 2 seconds
+    /// A duration of 1 second cannot be requested, since FSUIPC takes it as a request to
+    /// scroll the message until it is replaced. Use `PersistentScrolling` for that.
+    Scrolling,
+    /// The message scrolls along the window until it is replaced or cleared, ignoring the duration
+    PersistentScrolling,
+}
+
+/// Encode the value to be written into the message control offset
+/// FSUIPC displays the message for the given number of seconds if positive, scrolls it if
+/// negative and keeps it until replaced if zero. Durations must be between 1 and 32767 seconds.
+/// Scrolling messages must last 2 seconds at least, since FSUIPC scrolls the message until it
+/// is replaced for -1.
+pub(crate) fn encode_control(duration: Duration, mode: MessageMode) -> Result<i16> {
+    match mode {
+        MessageMode::Persistent => return Ok(0),
+        MessageMode::PersistentScrolling => return Ok(-1),
+        _ => {},
+    }
+    let secs = duration.as_secs();
+    let min_secs = if mode == MessageMode::Scrolling { 2 } else { 1 };
+    if secs < min_secs || secs > i16::MAX as u64 {
+        return Err(Error::OutOfRange { value: duration.as_secs_f64() });
+    }
+    match mode {
+        MessageMode::Scrolling => Ok(-(secs as i16)),
+        _ => Ok(secs as i16),
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::{Handle, Session};
+    use super::super::mock::MockHandle;
+
+    #[test]
+    fn should_encode_message_control() {
+        let ten = Duration::from_secs(10);
+        assert_eq!(encode_control(ten, MessageMode::Timed).unwrap(), 10);
+        assert_eq!(encode_control(ten, MessageMode::Scrolling).unwrap(), -10);
+        assert_eq!(encode_control(ten, MessageMode::Persistent).unwrap(), 0);
+        assert_eq!(encode_control(ten, MessageMode::PersistentScrolling).unwrap(), -1);
+        assert_eq!(encode_control(Duration::from_secs(1), MessageMode::Timed).unwrap(), 1);
+        assert_eq!(encode_control(Duration::from_secs(2), MessageMode::Scrolling).unwrap(), -2);
+        assert_eq!(encode_control(Duration::from_millis(0), MessageMode::Persistent).unwrap(), 0);
+    }
+
+    #[test]
+    fn should_fail_to_encode_invalid_durations() {
+        match encode_control(Duration::from_millis(500), MessageMode::Timed) {
+            Err(Error::OutOfRange { .. }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match encode_control(Duration::from_secs(1), MessageMode::Scrolling) {
+            Err(Error::OutOfRange { value }) => assert_eq!(value, 1.0),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match encode_control(Duration::from_secs(40000), MessageMode::Scrolling) {
+            Err(Error::OutOfRange { .. }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_display_and_clear_messages() {
+        let mut handle = MockHandle::new();
+        {
+            let mut session = handle.session();
+            session.display_message("Hello", Duration::from_secs(5), MessageMode::Scrolling)
+                .unwrap();
+            session.process().unwrap();
+        }
+        assert_eq!(handle.peek(0x3380, 6), b"Hello\0");
+        assert_eq!(handle.get::<i16>(0x32FA), -5);
+        {
+            let mut session = handle.session();
+            match session.display_message(&"x".repeat(128), Duration::from_secs(5),
+                MessageMode::Timed)
+            {
+                Err(Error::StringTooLong { len: 128, max_len: 128 }) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+            session.clear_message().unwrap();
+            session.process().unwrap();
+        }
+        assert_eq!(handle.get::<u8>(0x3380), 0);
+        assert_eq!(handle.get::<i16>(0x32FA), 0);
+    }
+}
@@ -0,0 +1,252 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::mem::size_of;
+
+use super::{BatchSession, Error, Exchange, Handle, OffsetValue, Result};
+use super::server::Dispatcher;
+
+/// A handle to a simulated FSUIPC backed by in-memory offsets
+/// This kind of handle does not require FSUIPC nor the simulator to be running. It holds the
+/// whole 64 KiB offset space in memory, so it can be used to test client code in any platform.
+/// Offset values can be seeded and inspected directly from the handle.
+pub struct MockHandle {
+    memory: Vec<u8>,
+    buffer_len: usize,
+    exchanges: usize,
+}
+
+impl MockHandle {
+    pub fn new() -> Self {
+        MockHandle::with_buffer_len(DEFAULT_BUFFER_LEN)
+    }
+
+    /// Create a new mock handle whose sessions are limited to the given number of bytes
+    /// By default, sessions are limited to 64 KiB as the file mapping of user handles.
+    pub fn with_buffer_len(buffer_len: usize) -> Self {
+        MockHandle { memory: vec![0; OFFSET_SPACE_LEN], buffer_len, exchanges: 0 }
+    }
+
+    /// The number of buffers exchanged with this handle so far
+    pub fn exchanges(&self) -> usize { self.exchanges }
+
+    /// Obtain the bytes stored in the given offset
+    pub fn peek(&self, offset: u16, len: usize) -> &[u8] {
+        let offset = offset as usize;
+        &self.memory[offset..offset + len]
+    }
+
+    /// Store the given bytes in the given offset
+    pub fn poke(&mut self, offset: u16, data: &[u8]) {
+        let offset = offset as usize;
+        self.memory[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Obtain the value stored in the given offset
+    /// The length of the offset is inferred from the type `T`.
+    pub fn get<T: OffsetValue>(&self, offset: u16) -> T {
+        T::decode_le(self.peek(offset, size_of::<T>()))
+    }
+
+    /// Store the given value in the given offset
+    /// The length of the offset is inferred from the type `T`.
+    pub fn set<T: OffsetValue>(&mut self, offset: u16, value: &T) {
+        let offset = offset as usize;
+        value.encode_le(&mut self.memory[offset..offset + size_of::<T>()]);
+    }
+}
+
+impl Default for MockHandle {
+    fn default() -> Self { MockHandle::new() }
+}
+
+impl Exchange for MockHandle {
+    fn buffer_len(&self) -> usize { self.buffer_len }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        if buffer.len() > self.buffer_len {
+            return Err(Error::BufferOverflow { required: buffer.len(), available: self.buffer_len });
+        }
+        self.exchanges += 1;
+        Dispatcher::new(&mut self.memory[..]).process(buffer)
+    }
+}
+
+impl<'a> Handle<'a> for MockHandle {
+    type Sess = MockSession<'a>;
+
+    fn session(&'a mut self) -> MockSession<'a> {
+        let buffer_len = self.buffer_len();
+        BatchSession::new(self, buffer_len)
+    }
+}
+
+/// A session of a `MockHandle`
+pub type MockSession<'a> = BatchSession<&'a mut MockHandle>;
+
+const OFFSET_SPACE_LEN: usize = 64*1024;
+const DEFAULT_BUFFER_LEN: usize = 64*1024;
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::{Handle, Session};
+
+    #[test]
+    fn should_read_seeded_offsets() {
+        let mut handle = MockHandle::new();
+        handle.set(0x3304, &0x49505553u32);
+        handle.set(0x3308, &8u16);
+        let mut fsuipc_ver = 0u32;
+        let mut fs_ver = 0u16;
+        {
+            let mut session = handle.session();
+            session.read(0x3304, &mut fsuipc_ver).unwrap();
+            session.read(0x3308, &mut fs_ver).unwrap();
+            session.process().unwrap();
+        }
+        assert_eq!(fsuipc_ver, 0x49505553);
+        assert_eq!(fs_ver, 8);
+    }
+
+    #[test]
+    fn should_write_offsets() {
+        let mut handle = MockHandle::new();
+        {
+            let mut session = handle.session();
+            session.write(0x0330, &(1020u16 * 16)).unwrap();
+            session.process().unwrap();
+        }
+        assert_eq!(handle.get::<u16>(0x0330), 1020 * 16);
+        assert_eq!(handle.peek(0x0330, 2), &[0xc0, 0x3f]);
+    }
+
+    #[test]
+    fn should_process_requests_in_order() {
+        let mut handle = MockHandle::new();
+        handle.poke(0x0238, &[10, 20, 30]);
+        let mut before = 0u8;
+        let mut after = 0u8;
+        {
+            let mut session = handle.session();
+            session.read(0x0239, &mut before).unwrap();
+            session.write(0x0239, &42u8).unwrap();
+            session.read(0x0239, &mut after).unwrap();
+            session.process().unwrap();
+        }
+        assert_eq!(before, 20);
+        assert_eq!(after, 42);
+        assert_eq!(handle.peek(0x0238, 3), &[10, 42, 30]);
+    }
+
+    #[test]
+    fn should_return_processed_bytes() {
+        let mut handle = MockHandle::new();
+        let mut value = 0u32;
+        let mut session = handle.session();
+        assert_eq!(session.read(0x3324, &mut value).unwrap(), 20);
+        assert_eq!(session.write(0x0330, &0u16).unwrap(), 14);
+        assert_eq!(session.process().unwrap(), 38);
+    }
+
+    #[test]
+    fn should_track_remaining_bytes() {
+        let mut handle = MockHandle::with_buffer_len(64);
+        let mut value = 0u32;
+        let mut session = handle.session();
+        assert_eq!(session.remaining(), 60);
+        session.read(0x3324, &mut value).unwrap();
+        assert_eq!(session.remaining(), 40);
+        session.write(0x0330, &0u16).unwrap();
+        assert_eq!(session.remaining(), 26);
+    }
+
+    #[test]
+    fn should_fail_to_request_beyond_buffer_len() {
+        let mut handle = MockHandle::with_buffer_len(64);
+        let mut value = 0u32;
+        let mut data = [0u8; 32];
+        {
+            let mut session = handle.session();
+            session.read(0x3324, &mut value).unwrap();
+            match session.read(0x3d00, &mut data) {
+                Err(Error::BufferOverflow { required: 48, available: 40 }) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+            match session.write(0x3d00, &[1u8; 40]) {
+                Err(Error::BufferOverflow { required: 52, available: 40 }) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+            session.write(0x3d00, &[1u8; 28]).unwrap();
+            assert_eq!(session.remaining(), 0);
+            session.process().unwrap();
+        }
+        assert_eq!(handle.peek(0x3d00, 28), &[1u8; 28]);
+    }
+
+    #[test]
+    fn should_split_requests_in_several_exchanges() {
+        let mut handle = MockHandle::with_buffer_len(64);
+        handle.poke(0x1000, &[1, 2, 3, 4]);
+        let mut before = [0u8; 4];
+        let mut after = [0u8; 4];
+        let nbytes = {
+            let mut session = handle.session();
+            session.set_auto_split(true);
+            session.read(0x1000, &mut before).unwrap();
+            session.write(0x1000, &[5u8, 6, 7, 8]).unwrap();
+            session.write(0x1002, &[9u8; 40]).unwrap();
+            session.read(0x1000, &mut after).unwrap();
+            session.process().unwrap()
+        };
+        assert_eq!(handle.exchanges(), 3);
+        assert_eq!(nbytes, (20 + 16 + 4) + (52 + 4) + (20 + 4));
+        assert_eq!(before, [1, 2, 3, 4]);
+        assert_eq!(after, [5, 6, 9, 9]);
+        assert_eq!(handle.peek(0x1000, 4), &[5, 6, 9, 9]);
+    }
+
+    #[test]
+    fn should_coalesce_contiguous_reads() {
+        let mut handle = MockHandle::new();
+        handle.poke(0x0238, &[12, 34, 56]);
+        let mut hour = 0u8;
+        let mut minute = 0u8;
+        let mut second = 0u8;
+        let mut time = [0u8; 2];
+        let mut after = 0u8;
+        let nbytes = {
+            let mut session = handle.session();
+            session.set_coalesce_reads(true);
+            session.read(0x023a, &mut second).unwrap();
+            session.read(0x0238, &mut hour).unwrap();
+            session.read(0x0239, &mut minute).unwrap();
+            session.read(0x0238, &mut time).unwrap();
+            session.write(0x0239, &42u8).unwrap();
+            session.read(0x0239, &mut after).unwrap();
+            session.process().unwrap()
+        };
+        assert_eq!((hour, minute, second), (12, 34, 56));
+        assert_eq!(time, [12, 34]);
+        assert_eq!(after, 42);
+        assert_eq!(nbytes, (16 + 3) + (12 + 1) + (16 + 1) + 4);
+    }
+
+    #[test]
+    fn should_fail_to_read_out_of_offset_space() {
+        let mut handle = MockHandle::new();
+        let mut value = 0u32;
+        let mut session = handle.session();
+        session.read(0xfffe, &mut value).unwrap();
+        match session.process() {
+            Err(Error::Rejected { code: 0 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
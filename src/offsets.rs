@@ -0,0 +1,262 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use super::bcd::Bcd;
+use super::units::*;
+
+/// A FSUIPC offset holding a value of type `T`
+/// It knows the address of the offset and the type of the value stored in it, so the size of
+/// the read and write operations is inferred from it. This module declares constants for the
+/// commonly used offsets, whose raw units are the ones documented by FSUIPC.
+pub struct Offset<T> {
+    address: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T> Offset<T> {
+    /// Declare an offset at the given address
+    pub const fn new(address: u16) -> Self {
+        Offset { address, _value: PhantomData }
+    }
+
+    /// The address of the offset
+    pub const fn address(&self) -> u16 { self.address }
+}
+
+impl<T> Clone for Offset<T> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<T> Copy for Offset<T> {}
+
+impl<T> fmt::Debug for Offset<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Offset(0x{:04X})", self.address)
+    }
+}
+
+impl<T> PartialEq for Offset<T> {
+    fn eq(&self, other: &Self) -> bool { self.address == other.address }
+}
+
+impl<T> Eq for Offset<T> {}
+
+// Version information
+
+/// FSUIPC version, as BCD in the high word (e.g., 0x3519 is 3.519) and the build letter in
+/// the low word (0 is none, 1 is 'a', and so on)
+pub const FSUIPC_VERSION: Offset<u32> = Offset::new(0x3304);
+/// Flight simulator version (7 for FS2004, 8 for FSX, 9 for ESP, 10 for P3D, 11 for FSX-SE and
+/// 12 for P3D 64-bit)
+pub const FS_VERSION: Offset<u16> = Offset::new(0x3308);
+
+// Simulation time
+
+/// Local hour (0-23)
+pub const LOCAL_HOUR: Offset<u8> = Offset::new(0x0238);
+/// Local minute (0-59)
+pub const LOCAL_MINUTE: Offset<u8> = Offset::new(0x0239);
+/// Second of the local and zulu time (0-59)
+pub const SECOND: Offset<u8> = Offset::new(0x023A);
+/// Zulu hour (0-23)
+pub const ZULU_HOUR: Offset<u8> = Offset::new(0x023B);
+/// Zulu minute (0-59)
+pub const ZULU_MINUTE: Offset<u8> = Offset::new(0x023C);
+/// Day of the year, counting from 1
+pub const DAY_OF_YEAR: Offset<u16> = Offset::new(0x023E);
+/// Year
+pub const YEAR: Offset<u16> = Offset::new(0x0240);
+/// Pause indicator, non-zero when the simulation is paused
+pub const PAUSE: Offset<u16> = Offset::new(0x0262);
+/// Simulation rate, as rate * 256
+pub const SIM_RATE: Offset<u16> = Offset::new(0x0C1A);
+
+// Position
+
+/// Latitude, as degrees * 10001750 * 65536 * 65536 / 90
+pub const LATITUDE: Offset<Latitude> = Offset::new(0x0560);
+/// Longitude, as degrees * 65536 * 65536 * 65536 * 65536 / 360
+pub const LONGITUDE: Offset<Longitude> = Offset::new(0x0568);
+/// Altitude above sea level, as metres * 65536 * 65536
+pub const ALTITUDE: Offset<Altitude> = Offset::new(0x0570);
+/// Altitude shown by the altimeter, in feet or metres depending on the simulator settings
+pub const INDICATED_ALTITUDE: Offset<i32> = Offset::new(0x3324);
+/// Altimeter pressure setting, as millibars * 16
+pub const ALTIMETER_SETTING: Offset<Pressure> = Offset::new(0x0330);
+/// On ground indicator, 1 when the aircraft is on the ground
+pub const ON_GROUND: Offset<u16> = Offset::new(0x0366);
+
+// Attitude
+
+/// Pitch, as degrees * 65536 * 65536 / 360 (negative is nose up)
+pub const PITCH: Offset<Angle> = Offset::new(0x0578);
+/// Bank, as degrees * 65536 * 65536 / 360 (negative is bank right)
+pub const BANK: Offset<Angle> = Offset::new(0x057C);
+/// True heading, as degrees * 65536 * 65536 / 360
+pub const HEADING: Offset<Heading> = Offset::new(0x0580);
+
+// Speeds
+
+/// Ground speed, as metres per second * 65536
+pub const GROUND_SPEED: Offset<u32> = Offset::new(0x02B4);
+/// True air speed, as knots * 128
+pub const TRUE_AIRSPEED: Offset<Airspeed> = Offset::new(0x02B8);
+/// Indicated air speed, as knots * 128
+pub const INDICATED_AIRSPEED: Offset<Airspeed> = Offset::new(0x02BC);
+/// Vertical speed, as metres per second * 256
+pub const VERTICAL_SPEED: Offset<VerticalSpeed> = Offset::new(0x02C8);
+
+// Radios
+
+/// ADF1 main frequency, as 3 digits in BCD (e.g., 0x0342 is 342 kHz)
+pub const ADF1_FREQUENCY: Offset<Bcd> = Offset::new(0x034C);
+/// ADF1 extended frequency, as the thousands digit in the high byte and the tenths digit in
+/// the low byte, both in BCD (e.g., 0x0105 with 0x0234 as main frequency is 1234.5 kHz)
+pub const ADF1_FREQUENCY_EXTENDED: Offset<Bcd> = Offset::new(0x0356);
+/// COM1 frequency, as 4 digits in BCD without the leading 1 (e.g., 0x2345 is 123.45 MHz)
+pub const COM1_FREQUENCY: Offset<Bcd> = Offset::new(0x034E);
+/// NAV1 frequency, as 4 digits in BCD without the leading 1 (e.g., 0x1030 is 110.30 MHz)
+pub const NAV1_FREQUENCY: Offset<Bcd> = Offset::new(0x0350);
+/// NAV2 frequency, as 4 digits in BCD without the leading 1
+pub const NAV2_FREQUENCY: Offset<Bcd> = Offset::new(0x0352);
+/// Transponder code, as 4 octal digits in BCD (e.g., 0x7000 is 7000)
+pub const TRANSPONDER_CODE: Offset<Bcd> = Offset::new(0x0354);
+/// COM2 frequency, as 4 digits in BCD without the leading 1
+pub const COM2_FREQUENCY: Offset<Bcd> = Offset::new(0x3118);
+/// COM1 standby frequency, as 4 digits in BCD without the leading 1
+pub const COM1_STANDBY_FREQUENCY: Offset<Bcd> = Offset::new(0x311A);
+/// COM2 standby frequency, as 4 digits in BCD without the leading 1
+pub const COM2_STANDBY_FREQUENCY: Offset<Bcd> = Offset::new(0x311C);
+/// NAV1 standby frequency, as 4 digits in BCD without the leading 1
+pub const NAV1_STANDBY_FREQUENCY: Offset<Bcd> = Offset::new(0x311E);
+/// NAV2 standby frequency, as 4 digits in BCD without the leading 1
+pub const NAV2_STANDBY_FREQUENCY: Offset<Bcd> = Offset::new(0x3120);
+/// COM1 frequency in Hz, supporting 8.33 kHz channels (see `bcd::com_hz_to_channel()`)
+pub const COM1_FREQUENCY_HZ: Offset<u32> = Offset::new(0x05C4);
+/// COM2 frequency in Hz, supporting 8.33 kHz channels
+pub const COM2_FREQUENCY_HZ: Offset<u32> = Offset::new(0x05C8);
+/// COM1 standby frequency in Hz, supporting 8.33 kHz channels
+pub const COM1_STANDBY_FREQUENCY_HZ: Offset<u32> = Offset::new(0x05CC);
+/// COM2 standby frequency in Hz, supporting 8.33 kHz channels
+pub const COM2_STANDBY_FREQUENCY_HZ: Offset<u32> = Offset::new(0x05D0);
+
+// Autopilot
+
+/// Autopilot master switch, non-zero when engaged
+pub const AP_MASTER: Offset<u32> = Offset::new(0x07BC);
+/// Autopilot heading lock, non-zero when engaged
+pub const AP_HEADING_LOCK: Offset<u32> = Offset::new(0x07C8);
+/// Autopilot heading, as degrees * 65536 / 360
+pub const AP_HEADING: Offset<u16> = Offset::new(0x07CC);
+/// Autopilot altitude lock, non-zero when engaged
+pub const AP_ALTITUDE_LOCK: Offset<u32> = Offset::new(0x07D0);
+/// Autopilot altitude, as metres * 65536
+pub const AP_ALTITUDE: Offset<u32> = Offset::new(0x07D4);
+/// Autopilot airspeed hold, non-zero when engaged
+pub const AP_AIRSPEED_HOLD: Offset<u32> = Offset::new(0x07DC);
+/// Autopilot airspeed, in knots
+pub const AP_AIRSPEED: Offset<u16> = Offset::new(0x07E2);
+/// Autopilot vertical speed hold, non-zero when engaged
+pub const AP_VERTICAL_SPEED_HOLD: Offset<u32> = Offset::new(0x07EC);
+/// Autopilot vertical speed, in feet per minute
+pub const AP_VERTICAL_SPEED: Offset<i16> = Offset::new(0x07F2);
+
+// Lights
+
+/// Lights, as a bit mask of the `LIGHT_*` constants
+pub const LIGHTS: Offset<u16> = Offset::new(0x0D0C);
+
+/// Navigation lights bit of `LIGHTS`
+pub const LIGHT_NAV: u16 = 1 << 0;
+/// Beacon lights bit of `LIGHTS`
+pub const LIGHT_BEACON: u16 = 1 << 1;
+/// Landing lights bit of `LIGHTS`
+pub const LIGHT_LANDING: u16 = 1 << 2;
+/// Taxi lights bit of `LIGHTS`
+pub const LIGHT_TAXI: u16 = 1 << 3;
+/// Strobe lights bit of `LIGHTS`
+pub const LIGHT_STROBES: u16 = 1 << 4;
+/// Instrument lights bit of `LIGHTS`
+pub const LIGHT_INSTRUMENTS: u16 = 1 << 5;
+/// Recognition lights bit of `LIGHTS`
+pub const LIGHT_RECOGNITION: u16 = 1 << 6;
+/// Wing lights bit of `LIGHTS`
+pub const LIGHT_WING: u16 = 1 << 7;
+/// Logo lights bit of `LIGHTS`
+pub const LIGHT_LOGO: u16 = 1 << 8;
+/// Cabin lights bit of `LIGHTS`
+pub const LIGHT_CABIN: u16 = 1 << 9;
+
+// Engines
+
+/// Engine 1 throttle lever, from -4096 (full reverse) to 16384 (full thrust)
+pub const ENGINE1_THROTTLE: Offset<i16> = Offset::new(0x088C);
+/// Engine 1 combustion flag, non-zero when the engine is running
+pub const ENGINE1_COMBUSTION: Offset<u16> = Offset::new(0x0894);
+/// Engine 1 jet N2, as percent * 16384 / 100
+pub const ENGINE1_N2: Offset<u16> = Offset::new(0x0896);
+/// Engine 1 jet N1, as percent * 16384 / 100
+pub const ENGINE1_N1: Offset<u16> = Offset::new(0x0898);
+/// Engine 2 throttle lever, from -4096 (full reverse) to 16384 (full thrust)
+pub const ENGINE2_THROTTLE: Offset<i16> = Offset::new(0x0924);
+/// Engine 2 combustion flag, non-zero when the engine is running
+pub const ENGINE2_COMBUSTION: Offset<u16> = Offset::new(0x092C);
+/// Engine 2 jet N2, as percent * 16384 / 100
+pub const ENGINE2_N2: Offset<u16> = Offset::new(0x092E);
+/// Engine 2 jet N1, as percent * 16384 / 100
+pub const ENGINE2_N1: Offset<u16> = Offset::new(0x0930);
+
+// Fuel
+
+/// Fuel weight, as pounds per gallon * 256
+pub const FUEL_WEIGHT: Offset<u16> = Offset::new(0x0AF4);
+/// Fuel level of the centre tank, as percent * 128 * 65536 / 100
+pub const FUEL_CENTRE_LEVEL: Offset<u32> = Offset::new(0x0B74);
+/// Fuel capacity of the centre tank, in US gallons
+pub const FUEL_CENTRE_CAPACITY: Offset<u32> = Offset::new(0x0B78);
+/// Fuel level of the left main tank, as percent * 128 * 65536 / 100
+pub const FUEL_LEFT_MAIN_LEVEL: Offset<u32> = Offset::new(0x0B7C);
+/// Fuel capacity of the left main tank, in US gallons
+pub const FUEL_LEFT_MAIN_CAPACITY: Offset<u32> = Offset::new(0x0B80);
+/// Fuel level of the right main tank, as percent * 128 * 65536 / 100
+pub const FUEL_RIGHT_MAIN_LEVEL: Offset<u32> = Offset::new(0x0B94);
+/// Fuel capacity of the right main tank, in US gallons
+pub const FUEL_RIGHT_MAIN_CAPACITY: Offset<u32> = Offset::new(0x0B98);
+
+// Controls
+
+/// Control to be sent to the simulator when written, with the parameter in `CONTROL_PARAMETER`
+pub const CONTROL: Offset<u32> = Offset::new(0x3110);
+/// Parameter of the control to be sent when `CONTROL` is written
+pub const CONTROL_PARAMETER: Offset<i32> = Offset::new(0x3114);
+
+// Lua plugins and macros
+
+/// Parameter of the command in `COMMAND`, written before it
+pub const COMMAND_PARAMETER: Offset<i32> = Offset::new(0x0D6C);
+/// Lua or macro command to be executed when written, as a zero-terminated string
+pub const COMMAND: Offset<[u8; 128]> = Offset::new(0x0D70);
+
+// Messages
+
+/// Message to be displayed in the simulator window, as a zero-terminated string
+pub const MESSAGE_TEXT: Offset<[u8; 128]> = Offset::new(0x3380);
+/// Display control of `MESSAGE_TEXT`, written after the text to display it
+pub const MESSAGE_CONTROL: Offset<i16> = Offset::new(0x32FA);
+
+// Aircraft
+
+/// Aircraft model, as a zero-terminated string
+pub const AIRCRAFT_MODEL: Offset<[u8; 24]> = Offset::new(0x3500);
+/// Aircraft title, as a zero-terminated string
+pub const AIRCRAFT_TITLE: Offset<[u8; 256]> = Offset::new(0x3D00);
+/// ATC identifier (tail number), as a zero-terminated string
+pub const ATC_IDENTIFIER: Offset<[u8; 12]> = Offset::new(0x313C);
@@ -0,0 +1,231 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::mem::size_of;
+
+use super::{Error, Exchange, Offset, OffsetValue, Result};
+use super::ipc::*;
+use super::safe::{self, ReadToken};
+
+/// The location of a read value in the buffers of a prepared session
+struct Slot {
+    offset: u16,
+    buffer: usize,
+    pos: usize,
+    len: usize,
+}
+
+/// A prepared set of read operations that can be executed repeatedly
+/// The read requests are encoded once in as many buffers as needed for the handle the session
+/// is prepared for. Each execution exchanges the same buffers with FSUIPC, and the values are
+/// fetched by token from the responses left in them. Thus, no encoding nor allocation is done
+/// after the session is prepared.
+pub struct Prepared {
+    id: usize,
+    prefix: usize,
+    capacity: usize,
+    buffers: Vec<Vec<u8>>,
+    slots: Vec<Slot>,
+}
+
+impl Prepared {
+    /// Create a new prepared session for the given handle
+    pub fn new<E: Exchange>(handle: &E) -> Self {
+        Prepared {
+            id: safe::next_session_id(),
+            prefix: handle.prefix_len(),
+            capacity: handle.buffer_len(),
+            buffers: Vec::new(),
+            slots: Vec::new(),
+        }
+    }
+
+    /// Request to read from the given offset in each execution
+    /// The length of the offset is inferred from the type `T`.
+    pub fn read<T: OffsetValue>(&mut self, offset: u16) -> Result<ReadToken<T>> {
+        let len = size_of::<T>();
+        let required = RSD_HEADER_LEN + len;
+        let fits = match self.buffers.last() {
+            Some(buffer) => {
+                let used = buffer.len() - self.prefix - TM_HEADER_LEN;
+                ensure_room(required, self.capacity - used).is_ok()
+            },
+            None => false,
+        };
+        if !fits {
+            ensure_room(required, self.capacity)?;
+            let mut buffer = vec![0; self.prefix];
+            buffer.write_header(&MsgHeader::TerminationMark)?;
+            self.buffers.push(buffer);
+        }
+        let index = self.slots.len();
+        let buffer = self.buffers.last_mut().unwrap();
+        let end = buffer.len() - TM_HEADER_LEN;
+        buffer.truncate(end);
+        buffer.write_rsd(offset, index as *mut u8, len)?;
+        buffer.write_header(&MsgHeader::TerminationMark)?;
+        self.slots.push(Slot {
+            offset,
+            buffer: self.buffers.len() - 1,
+            pos: end + RSD_HEADER_LEN,
+            len,
+        });
+        Ok(ReadToken::new(self.id, index))
+    }
+
+    /// Request to read from the given typed offset in each execution
+    pub fn read_offset<T: OffsetValue>(&mut self, offset: Offset<T>) -> Result<ReadToken<T>> {
+        self.read(offset.address())
+    }
+
+    /// Execute the read operations against the given handle
+    /// The handle must be the one the session was prepared for, or another one with the same
+    /// buffer layout. Otherwise, it fails with `Error::HandleMismatch`. It returns the number of
+    /// bytes of requests processed.
+    pub fn execute<E: Exchange>(&mut self, handle: &mut E) -> Result<usize> {
+        if handle.prefix_len() != self.prefix || handle.buffer_len() < self.capacity {
+            return Err(Error::HandleMismatch);
+        }
+        let mut nbytes = 0;
+        for i in 0..self.buffers.len() {
+            if let Err(e) = handle.exchange(&mut self.buffers[i]) {
+                self.encode(i)?;
+                return Err(e);
+            }
+            nbytes += self.buffers[i].len() - self.prefix;
+        }
+        Ok(nbytes)
+    }
+
+    /// Encode again the requests of the given buffer
+    /// A failed exchange may leave anything in the buffer, so it is encoded again in place for
+    /// the next execution.
+    fn encode(&mut self, buffer: usize) -> Result<()> {
+        let data = &mut self.buffers[buffer];
+        for byte in &mut data[..self.prefix] {
+            *byte = 0;
+        }
+        for (index, slot) in self.slots.iter().enumerate().filter(|&(_, s)| s.buffer == buffer) {
+            let mut request = &mut data[slot.pos - RSD_HEADER_LEN..slot.pos + slot.len];
+            request.write_rsd(slot.offset, index as *mut u8, slot.len)?;
+        }
+        let end = data.len() - TM_HEADER_LEN;
+        (&mut data[end..]).write_header(&MsgHeader::TerminationMark)?;
+        Ok(())
+    }
+
+    /// Obtain the value read for the given token in the last execution
+    /// It panics if the token was not obtained from this prepared session.
+    pub fn get<T: OffsetValue>(&self, token: &ReadToken<T>) -> T {
+        let slot = &self.slots[token.index_in(self.id)];
+        T::decode_le(&self.buffers[slot.buffer][slot.pos..slot.pos + slot.len])
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::mock::MockHandle;
+
+    /// A handle that garbles the buffer and fails in the first exchange
+    struct FailingOnce {
+        mock: MockHandle,
+        failed: bool,
+    }
+
+    impl Exchange for FailingOnce {
+        fn buffer_len(&self) -> usize { self.mock.buffer_len() }
+
+        fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+            if !self.failed {
+                self.failed = true;
+                for byte in buffer.iter_mut() {
+                    *byte = 0xff;
+                }
+                return Err(Error::Timeout);
+            }
+            self.mock.exchange(buffer)
+        }
+    }
+
+    #[test]
+    fn should_refill_values_on_each_execution() {
+        let mut handle = MockHandle::new();
+        let mut prepared = Prepared::new(&handle);
+        let altitude = prepared.read::<u32>(0x3324).unwrap();
+        let time = prepared.read::<[u8; 3]>(0x0238).unwrap();
+
+        handle.set(0x3324, &1000u32);
+        handle.poke(0x0238, &[10, 20, 30]);
+        assert_eq!(prepared.execute(&mut handle).unwrap(), 20 + 19 + 4);
+        assert_eq!(prepared.get(&altitude), 1000);
+        assert_eq!(prepared.get(&time), [10, 20, 30]);
+
+        handle.set(0x3324, &2000u32);
+        handle.poke(0x0238, &[10, 20, 31]);
+        prepared.execute(&mut handle).unwrap();
+        assert_eq!(prepared.get(&altitude), 2000);
+        assert_eq!(prepared.get(&time), [10, 20, 31]);
+        assert_eq!(handle.exchanges(), 2);
+    }
+
+    #[test]
+    fn should_split_reads_in_several_buffers() {
+        let mut handle = MockHandle::with_buffer_len(64);
+        let mut prepared = Prepared::new(&handle);
+        let tokens: Vec<_> = (0..4).map(|i| prepared.read::<u64>(0x1000 + i * 8).unwrap()).collect();
+        for i in 0..4 {
+            handle.set(0x1000 + i * 8, &(i as u64 + 1));
+        }
+        prepared.execute(&mut handle).unwrap();
+        assert_eq!(handle.exchanges(), 2);
+        for (i, token) in tokens.iter().enumerate() {
+            assert_eq!(prepared.get(token), i as u64 + 1);
+        }
+    }
+
+    #[test]
+    fn should_fail_to_prepare_read_larger_than_buffer() {
+        let handle = MockHandle::with_buffer_len(64);
+        let mut prepared = Prepared::new(&handle);
+        match prepared.read::<[u8; 48]>(0x1000) {
+            Err(Error::BufferOverflow { required: 64, available: 60 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_execute_again_after_failed_exchange() {
+        let mut handle = FailingOnce { mock: MockHandle::new(), failed: false };
+        handle.mock.set(0x3324, &1000u32);
+        let mut prepared = Prepared::new(&handle);
+        let altitude = prepared.read::<u32>(0x3324).unwrap();
+        let time = prepared.read::<[u8; 3]>(0x0238).unwrap();
+        match prepared.execute(&mut handle) {
+            Err(Error::Timeout) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        handle.mock.poke(0x0238, &[10, 20, 30]);
+        assert_eq!(prepared.execute(&mut handle).unwrap(), 20 + 19 + 4);
+        assert_eq!(prepared.get(&altitude), 1000);
+        assert_eq!(prepared.get(&time), [10, 20, 30]);
+    }
+
+    #[test]
+    fn should_fail_to_execute_against_other_handle() {
+        let mut prepared = Prepared::new(&MockHandle::new());
+        prepared.read::<u32>(0x3324).unwrap();
+        let mut handle = MockHandle::with_buffer_len(64);
+        match prepared.execute(&mut handle) {
+            Err(Error::HandleMismatch) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(handle.exchanges(), 0);
+    }
+}
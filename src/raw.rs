@@ -13,49 +13,48 @@ use std::cmp::min;
 pub struct RawBytes {
     data: *const u8,
     len: usize,
-    read: usize,
 }
 
 impl RawBytes {
     pub fn new(data: *const u8, len: usize) -> Self {
-        RawBytes { data: data, len: len, read: 0 }
+        RawBytes { data, len }
     }
-
-    pub fn consumed(&self) -> usize { self.read }
 }
 
 impl io::Read for RawBytes {
     fn read(&mut self, buff: &mut [u8]) -> io::Result<usize> {
         unsafe {
             let nbytes = min(self.len, buff.len());
-            for i in 0..nbytes {
-                buff[i] = *self.data;
+            for b in buff.iter_mut().take(nbytes) {
+                *b = *self.data;
                 self.data = self.data.offset(1);
                 self.len -= 1;
-                self.read += 1;
             }
             Ok(nbytes)
         }
     }
 }
 
+#[cfg(any(windows, test))]
 pub struct MutRawBytes {
     data: *mut u8,
     len: usize,
 }
 
+#[cfg(any(windows, test))]
 impl MutRawBytes {
     pub fn new(data: *mut u8, len: usize) -> Self {
-        MutRawBytes { data: data, len: len }
+        MutRawBytes { data, len }
     }
 }
 
+#[cfg(any(windows, test))]
 impl io::Write for MutRawBytes {
     fn write(&mut self, buff: &[u8]) -> io::Result<usize> {
         unsafe {
             let nbytes = min(self.len, buff.len());
-            for i in 0..nbytes {
-                *self.data = buff[i];
+            for b in buff.iter().take(nbytes) {
+                *self.data = *b;
                 self.data = self.data.offset(1);
                 self.len -= 1;
             }
@@ -109,17 +108,6 @@ mod test {
         assert_eq!(dest[5], 0);
     }
 
-    #[test]
-    fn should_count_consumed_for_mutrawbytes() {
-        let src = [1u8, 2, 3, 4];
-        let mut dest = [0, 0];
-        let mut raw = RawBytes::new(&src as *const u8, 4);
-        raw.read(&mut dest).unwrap();
-        assert_eq!(raw.consumed(), 2);
-        raw.read(&mut dest).unwrap();
-        assert_eq!(raw.consumed(), 4);
-    }
-
     #[test]
     fn should_write_to_mutrawbytes() {
         let src = [1u8, 2, 3, 4];
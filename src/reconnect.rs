@@ -0,0 +1,299 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use super::{BatchSession, Error, Exchange, Handle, Result};
+
+/// A change in the connection state of a `ReconnectingHandle`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// The connection was established again
+    Connected,
+    /// The connection was lost after a failed exchange
+    Disconnected,
+    /// An attempt to connect failed, and the next one is delayed the given time
+    ConnectFailed { retry_in: Duration },
+}
+
+type Connect<E> = Box<dyn FnMut() -> Result<E> + Send>;
+type Init<E> = Box<dyn FnMut(&mut E) -> Result<()> + Send>;
+type Listener = Box<dyn FnMut(&ConnectionEvent) + Send>;
+
+/// A handle that connects to FSUIPC again when the connection is lost
+/// It wraps the handles created by a connect function (e.g., `UserHandle::new`). When an
+/// exchange fails because FSUIPC is gone or does not respond, the wrapped handle is dropped
+/// and a new one is created on the next exchange. Failed attempts are retried with an
+/// exponential backoff, failing with `Error::NotRunning` meanwhile.
+pub struct ReconnectingHandle<E> {
+    handle: Option<E>,
+    connect: Connect<E>,
+    init: Option<Init<E>>,
+    listener: Option<Listener>,
+    buffer_len: usize,
+    prefix_len: usize,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl<E: Exchange> ReconnectingHandle<E> {
+    /// Create a new handle, connecting with the given function
+    /// The first connection is established before returning.
+    pub fn new<F>(mut connect: F) -> Result<Self>
+        where F: FnMut() -> Result<E> + Send + 'static
+    {
+        let handle = connect()?;
+        let initial_backoff = Duration::from_millis(500);
+        Ok(ReconnectingHandle {
+            buffer_len: handle.buffer_len(),
+            prefix_len: handle.prefix_len(),
+            handle: Some(handle),
+            connect: Box::new(connect),
+            init: None,
+            listener: None,
+            initial_backoff,
+            max_backoff: Duration::from_secs(30),
+            backoff: initial_backoff,
+            next_attempt: Instant::now(),
+        })
+    }
+
+    /// Set the delays between failed connection attempts
+    /// The delay starts at `initial` and it is doubled after each failed attempt up to `max`.
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self.backoff = initial;
+    }
+
+    /// Set a function to initialise each new connection
+    /// It is called after reconnecting and before any other exchange, so it can be used to
+    /// replay the writes that configure the simulator. If it fails, the connection is dropped
+    /// and retried as any other failed attempt.
+    pub fn set_init<F>(&mut self, init: F)
+        where F: FnMut(&mut E) -> Result<()> + Send + 'static
+    {
+        self.init = Some(Box::new(init));
+    }
+
+    /// Set a function to be notified of the changes in the connection state
+    pub fn set_listener<F>(&mut self, listener: F)
+        where F: FnMut(&ConnectionEvent) + Send + 'static
+    {
+        self.listener = Some(Box::new(listener));
+    }
+
+    /// Whether the handle is connected to FSUIPC
+    pub fn is_connected(&self) -> bool { self.handle.is_some() }
+
+    fn notify(&mut self, event: ConnectionEvent) {
+        if let Some(ref mut listener) = self.listener {
+            listener(&event);
+        }
+    }
+
+    fn ensure_connected(&mut self) -> Result<&mut E> {
+        if self.handle.is_none() {
+            let now = Instant::now();
+            if now < self.next_attempt {
+                return Err(Error::NotRunning);
+            }
+            match self.reconnect() {
+                Ok(handle) => {
+                    self.handle = Some(handle);
+                    self.backoff = self.initial_backoff;
+                    self.notify(ConnectionEvent::Connected);
+                },
+                Err(e) => {
+                    let retry_in = self.backoff;
+                    self.next_attempt = now + retry_in;
+                    self.backoff = cmp::min(retry_in * 2, self.max_backoff);
+                    self.notify(ConnectionEvent::ConnectFailed { retry_in });
+                    return Err(e);
+                },
+            }
+        }
+        Ok(self.handle.as_mut().unwrap())
+    }
+
+    fn reconnect(&mut self) -> Result<E> {
+        let mut handle = (self.connect)()?;
+        if let Some(ref mut init) = self.init {
+            init(&mut handle)?;
+        }
+        Ok(handle)
+    }
+}
+
+/// Whether the error means that the connection with FSUIPC is lost
+fn is_connection_failure(error: &Error) -> bool {
+    matches!(*error,
+        Error::NotRunning |
+        Error::RegisterMessageFailed |
+        Error::AtomCreationFailed |
+        Error::MappingFailed |
+        Error::Timeout |
+        Error::Rejected { .. } |
+        Error::Io(_))
+}
+
+impl<E: Exchange> Exchange for ReconnectingHandle<E> {
+    fn prefix_len(&self) -> usize { self.prefix_len }
+
+    fn buffer_len(&self) -> usize { self.buffer_len }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let result = self.ensure_connected()?.exchange(buffer);
+        if let Err(ref e) = result {
+            if is_connection_failure(e) {
+                self.handle = None;
+                self.next_attempt = Instant::now();
+                self.notify(ConnectionEvent::Disconnected);
+            }
+        }
+        result
+    }
+}
+
+impl<'a, E: Exchange + 'a> Handle<'a> for ReconnectingHandle<E> {
+    type Sess = ReconnectingSession<'a, E>;
+
+    fn session(&'a mut self) -> ReconnectingSession<'a, E> {
+        let buffer_len = self.buffer_len;
+        BatchSession::new(self, buffer_len)
+    }
+}
+
+/// A session of a `ReconnectingHandle`
+pub type ReconnectingSession<'a, E> = BatchSession<&'a mut ReconnectingHandle<E>>;
+
+#[cfg(test)]
+mod test {
+
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::Session;
+    use super::super::mock::MockHandle;
+    use super::super::shared::SharedHandle;
+
+    /// A connection to a simulator that can be shut down
+    struct Flaky {
+        sim: SharedHandle<MockHandle>,
+        down: Arc<AtomicBool>,
+    }
+
+    impl Exchange for Flaky {
+        fn buffer_len(&self) -> usize { self.sim.buffer_len() }
+
+        fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(Error::Timeout);
+            }
+            self.sim.exchange(buffer)
+        }
+    }
+
+    fn connect_to(sim: &SharedHandle<MockHandle>, down: &Arc<AtomicBool>)
+        -> ReconnectingHandle<Flaky>
+    {
+        let (sim, down) = (sim.clone(), down.clone());
+        ReconnectingHandle::new(move || {
+            if down.load(Ordering::SeqCst) {
+                return Err(Error::NotRunning);
+            }
+            Ok(Flaky { sim: sim.clone(), down: down.clone() })
+        }).unwrap()
+    }
+
+    fn read_version(handle: &mut ReconnectingHandle<Flaky>) -> Result<u32> {
+        let mut value = 0u32;
+        let mut session = handle.session();
+        session.read(0x3304, &mut value)?;
+        session.process()?;
+        Ok(value)
+    }
+
+    #[test]
+    fn should_reconnect_after_failure() {
+        let sim = SharedHandle::new(MockHandle::new());
+        sim.lock().set(0x3304, &1u32);
+        let down = Arc::new(AtomicBool::new(false));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut handle = connect_to(&sim, &down);
+        handle.set_backoff(Duration::from_millis(0), Duration::from_millis(0));
+        {
+            let events = events.clone();
+            handle.set_listener(move |event| events.lock().unwrap().push(event.clone()));
+        }
+        assert_eq!(read_version(&mut handle).unwrap(), 1);
+
+        down.store(true, Ordering::SeqCst);
+        match read_version(&mut handle) {
+            Err(Error::Timeout) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!handle.is_connected());
+        match read_version(&mut handle) {
+            Err(Error::NotRunning) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        down.store(false, Ordering::SeqCst);
+        sim.lock().set(0x3304, &2u32);
+        assert_eq!(read_version(&mut handle).unwrap(), 2);
+        assert!(handle.is_connected());
+        assert_eq!(*events.lock().unwrap(), vec![
+            ConnectionEvent::Disconnected,
+            ConnectionEvent::ConnectFailed { retry_in: Duration::from_millis(0) },
+            ConnectionEvent::Connected,
+        ]);
+    }
+
+    #[test]
+    fn should_back_off_between_attempts() {
+        let sim = SharedHandle::new(MockHandle::new());
+        let down = Arc::new(AtomicBool::new(false));
+        let mut handle = connect_to(&sim, &down);
+        handle.set_backoff(Duration::from_secs(60), Duration::from_secs(60));
+        down.store(true, Ordering::SeqCst);
+        assert!(read_version(&mut handle).is_err());
+        assert!(read_version(&mut handle).is_err());
+
+        down.store(false, Ordering::SeqCst);
+        match read_version(&mut handle) {
+            Err(Error::NotRunning) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!handle.is_connected());
+    }
+
+    #[test]
+    fn should_initialise_new_connections() {
+        let sim = SharedHandle::new(MockHandle::new());
+        let down = Arc::new(AtomicBool::new(false));
+        let mut handle = connect_to(&sim, &down);
+        handle.set_init(|flaky| {
+            let mut session = flaky.sim.session();
+            session.write(0x0330, &(1013u16 * 16))?;
+            session.process().map(|_| ())
+        });
+        down.store(true, Ordering::SeqCst);
+        assert!(read_version(&mut handle).is_err());
+        assert_eq!(sim.lock().get::<u16>(0x0330), 0);
+
+        down.store(false, Ordering::SeqCst);
+        read_version(&mut handle).unwrap();
+        assert_eq!(sim.lock().get::<u16>(0x0330), 1013 * 16);
+    }
+}
@@ -0,0 +1,373 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::{Read, Write};
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{BatchSession, Error, Exchange, Handle, Result};
+use super::ipc::MAX_BUFFER_LEN;
+
+// A recording starts with the `MAGIC` bytes, followed by the version of the format and the
+// buffer length of the recorded handle as u32. Then, each exchange is recorded as:
+//
+// * The time it was done, as microseconds since the UNIX epoch in u64
+// * The request buffer, as its length in u32 followed by the buffer without prefix
+// * A u8 status. If it is `STATUS_OK`, it is followed by the response buffer, whose length is
+//   the same as the request. Otherwise, it is followed by the error (see `Error::write_to()`).
+//
+// All integers are little-endian.
+const MAGIC: &[u8; 8] = b"FSUIPCRC";
+const VERSION: u32 = 1;
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/// An exchange with FSUIPC read from a recording
+pub struct Record {
+    /// The time the exchange was done
+    pub timestamp: SystemTime,
+    /// The request buffer, with the IPC messages and the termination mark
+    pub request: Vec<u8>,
+    /// The response buffer left by FSUIPC, or the error it produced
+    pub response: Result<Vec<u8>>,
+}
+
+/// A handle that records the exchanges of another handle
+/// Each exchange is written to the output along with its response as soon as it is done, so
+/// the recording is complete even if the application crashes. The recording can be served
+/// back by a `ReplayHandle` to reproduce the exchanges without FSUIPC.
+///
+/// Failing to write the output does not fail the exchanges. Instead, the recording is stopped
+/// and the error is kept to be checked with `error()`.
+pub struct RecordingHandle<E, W> {
+    handle: E,
+    output: W,
+    request: Vec<u8>,
+    error: Option<io::Error>,
+}
+
+impl<E: Exchange, W: Write> RecordingHandle<E, W> {
+    /// Create a new handle recording the exchanges of `handle` into `output`
+    pub fn new(handle: E, mut output: W) -> Result<Self> {
+        output.write_all(MAGIC)?;
+        output.write_u32::<LittleEndian>(VERSION)?;
+        output.write_u32::<LittleEndian>(handle.buffer_len() as u32)?;
+        output.flush()?;
+        Ok(RecordingHandle { handle, output, request: Vec::new(), error: None })
+    }
+
+    /// The error that stopped the recording, if any
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Stop recording, returning the wrapped handle and the output
+    pub fn into_inner(self) -> (E, W) {
+        (self.handle, self.output)
+    }
+
+    fn record(&mut self, response: result::Result<&[u8], &Error>) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.output.write_u64::<LittleEndian>(timestamp.as_micros() as u64)?;
+        self.output.write_u32::<LittleEndian>(self.request.len() as u32)?;
+        self.output.write_all(&self.request)?;
+        match response {
+            Ok(data) => {
+                self.output.write_u8(STATUS_OK)?;
+                self.output.write_all(data)?;
+            },
+            Err(e) => {
+                self.output.write_u8(STATUS_ERROR)?;
+                e.write_to(&mut self.output)?;
+            },
+        }
+        self.output.flush()
+    }
+}
+
+impl<E: Exchange, W: Write> Exchange for RecordingHandle<E, W> {
+    fn prefix_len(&self) -> usize { self.handle.prefix_len() }
+
+    fn buffer_len(&self) -> usize { self.handle.buffer_len() }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        if self.error.is_some() {
+            return self.handle.exchange(buffer);
+        }
+        let prefix = self.prefix_len();
+        self.request.clear();
+        self.request.extend_from_slice(&buffer[prefix..]);
+        let result = self.handle.exchange(buffer);
+        if let Err(e) = self.record(result.as_ref().map(|_| &buffer[prefix..])) {
+            self.error = Some(e);
+        }
+        result
+    }
+}
+
+impl<'a, E: Exchange + 'a, W: Write + 'a> Handle<'a> for RecordingHandle<E, W> {
+    type Sess = RecordingSession<'a, E, W>;
+
+    fn session(&'a mut self) -> RecordingSession<'a, E, W> {
+        let buffer_len = self.buffer_len();
+        BatchSession::new(self, buffer_len)
+    }
+}
+
+/// A session of a `RecordingHandle`
+pub type RecordingSession<'a, E, W> = BatchSession<&'a mut RecordingHandle<E, W>>;
+
+/// Read the exchanges recorded by a `RecordingHandle`
+/// It returns the buffer length of the recorded handle and the recorded exchanges. Records
+/// longer than the buffers FSUIPC can exchange are rejected as invalid data. A partial record
+/// at the end, left by an application that stopped while recording, is dropped.
+pub fn read_records<R: Read>(mut input: R) -> Result<(usize, Vec<Record>)> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    let version = input.read_u32::<LittleEndian>()?;
+    if &magic != MAGIC || version != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a FSUIPC recording").into());
+    }
+    let buffer_len = input.read_u32::<LittleEndian>()? as usize;
+    let mut records = Vec::new();
+    loop {
+        match read_record(&mut input) {
+            Ok(record) => records.push(record),
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((buffer_len, records))
+}
+
+fn read_record<R: Read>(input: &mut R) -> Result<Record> {
+    let micros = input.read_u64::<LittleEndian>()?;
+    let len = input.read_u32::<LittleEndian>()? as usize;
+    if len > MAX_BUFFER_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "record too long").into());
+    }
+    let mut request = vec![0; len];
+    input.read_exact(&mut request)?;
+    let response = match input.read_u8()? {
+        STATUS_OK => {
+            let mut response = vec![0; len];
+            input.read_exact(&mut response)?;
+            Ok(response)
+        },
+        _ => Err(Error::read_from(input)?),
+    };
+    let timestamp = UNIX_EPOCH + Duration::from_micros(micros);
+    Ok(Record { timestamp, request, response })
+}
+
+/// A handle that serves the responses of a recording
+/// The exchanges must be the same as the recorded ones, in the same order. Otherwise, the
+/// exchange fails with `Error::ReplayMismatch` and the record is kept to be replayed by a later
+/// exchange. Thus, a session processed against FSUIPC can be reproduced deterministically
+/// without it.
+pub struct ReplayHandle {
+    buffer_len: usize,
+    records: ::std::vec::IntoIter<Record>,
+    exchanges: usize,
+}
+
+impl ReplayHandle {
+    /// Create a new handle replaying the recording read from the given input
+    pub fn open<R: Read>(input: R) -> Result<Self> {
+        let (buffer_len, records) = read_records(input)?;
+        Ok(Self::new(buffer_len, records))
+    }
+
+    /// Create a new handle replaying the given records
+    pub fn new(buffer_len: usize, records: Vec<Record>) -> Self {
+        ReplayHandle { buffer_len, records: records.into_iter(), exchanges: 0 }
+    }
+
+    /// The number of recorded exchanges not replayed yet
+    pub fn remaining(&self) -> usize { self.records.len() }
+}
+
+impl Exchange for ReplayHandle {
+    fn buffer_len(&self) -> usize { self.buffer_len }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let exchange = self.exchanges;
+        self.exchanges += 1;
+        match self.records.as_slice().first() {
+            Some(record) if record.request == buffer &&
+                record.response.as_ref().map_or(true, |r| r.len() == buffer.len()) => {},
+            _ => return Err(Error::ReplayMismatch { exchange }),
+        }
+        match self.records.next().map(|record| record.response) {
+            Some(Ok(response)) => {
+                buffer.copy_from_slice(&response);
+                Ok(())
+            },
+            Some(Err(e)) => Err(e),
+            None => Err(Error::ReplayMismatch { exchange }),
+        }
+    }
+}
+
+impl<'a> Handle<'a> for ReplayHandle {
+    type Sess = ReplaySession<'a>;
+
+    fn session(&'a mut self) -> ReplaySession<'a> {
+        let buffer_len = self.buffer_len;
+        BatchSession::new(self, buffer_len)
+    }
+}
+
+/// A session of a `ReplayHandle`
+pub type ReplaySession<'a> = BatchSession<&'a mut ReplayHandle>;
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use super::super::Session;
+    use super::super::mock::MockHandle;
+
+    fn read_altitude<'a, H: Handle<'a>>(handle: &'a mut H, qnh: u16) -> Result<i32> {
+        let mut altitude = 0i32;
+        let mut session = handle.session();
+        session.write(0x0330, &qnh)?;
+        session.read(0x3324, &mut altitude)?;
+        session.process()?;
+        Ok(altitude)
+    }
+
+    fn record() -> Vec<u8> {
+        let mut mock = MockHandle::new();
+        mock.set(0x3324, &3500i32);
+        let mut recording = RecordingHandle::new(mock, Vec::new()).unwrap();
+        assert_eq!(read_altitude(&mut recording, 1013 * 16).unwrap(), 3500);
+        {
+            let mut value = 0u32;
+            let mut session = recording.session();
+            session.read(0xfffe, &mut value).unwrap();
+            assert!(session.process().is_err());
+        }
+        recording.into_inner().1
+    }
+
+    #[test]
+    fn should_record_exchanges() {
+        let (buffer_len, records) = read_records(&record()[..]).unwrap();
+        assert_eq!(buffer_len, 64 * 1024);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].request.len(), 14 + 20 + 4);
+        match records[0].response {
+            Ok(ref response) => assert_eq!(&response[30..34], &3500i32.to_le_bytes()),
+            Err(ref e) => panic!("unexpected error: {:?}", e),
+        }
+        match records[1].response {
+            Err(Error::Rejected { code: 0 }) => {},
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    #[test]
+    fn should_drop_partial_records_at_the_end() {
+        let recording = record();
+        let (_, records) = read_records(&recording[..]).unwrap();
+        let header_len = MAGIC.len() + 4 + 4;
+        let first_len = 8 + 4 + 2 * records[0].request.len() + 1;
+        let total_len = recording.len() - header_len;
+        for cut in [1, 12, 20, first_len - 1, first_len + 10, total_len - 1] {
+            let (_, truncated) = read_records(&recording[..header_len + cut]).unwrap();
+            assert_eq!(truncated.len(), if cut < first_len { 0 } else { 1 });
+        }
+    }
+
+    struct FailingOutput;
+
+    impl Write for FailingOutput {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn should_not_fail_exchanges_when_recording_fails() {
+        let mut mock = MockHandle::new();
+        mock.set(0x3324, &3500i32);
+        let mut recording = RecordingHandle {
+            handle: mock, output: FailingOutput, request: Vec::new(), error: None };
+        assert_eq!(read_altitude(&mut recording, 1013 * 16).unwrap(), 3500);
+        assert_eq!(recording.error().unwrap().to_string(), "disk full");
+        assert_eq!(read_altitude(&mut recording, 1013 * 16).unwrap(), 3500);
+    }
+
+    #[test]
+    fn should_replay_recorded_exchanges() {
+        let mut replay = ReplayHandle::open(&record()[..]).unwrap();
+        assert_eq!(replay.remaining(), 2);
+        assert_eq!(read_altitude(&mut replay, 1013 * 16).unwrap(), 3500);
+        let mut value = 0u32;
+        let mut session = replay.session();
+        session.read(0xfffe, &mut value).unwrap();
+        match session.process() {
+            Err(Error::Rejected { code: 0 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn should_fail_to_replay_different_exchanges() {
+        let mut replay = ReplayHandle::open(&record()[..]).unwrap();
+        match read_altitude(&mut replay, 1020 * 16) {
+            Err(Error::ReplayMismatch { exchange: 0 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(replay.remaining(), 2);
+        assert_eq!(read_altitude(&mut replay, 1013 * 16).unwrap(), 3500);
+        assert_eq!(replay.remaining(), 1);
+    }
+
+    #[test]
+    fn should_fail_to_replay_responses_of_other_length() {
+        let request = vec![0; 4];
+        let mut replay = ReplayHandle::new(64, vec![Record {
+            timestamp: UNIX_EPOCH,
+            request: request.clone(),
+            response: Ok(vec![0; 8]),
+        }]);
+        let mut buffer = request.clone();
+        match replay.exchange(&mut buffer) {
+            Err(Error::ReplayMismatch { exchange: 0 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(replay.remaining(), 1);
+    }
+
+    #[test]
+    fn should_fail_to_read_records_too_long() {
+        let mut recording = record();
+        let header_len = MAGIC.len() + 4 + 4;
+        (&mut recording[header_len + 8..]).write_u32::<LittleEndian>(u32::MAX).unwrap();
+        match read_records(&recording[..]) {
+            Err(Error::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn should_fail_to_open_other_files() {
+        match ReplayHandle::open(&b"not a recording"[..]) {
+            Err(Error::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("unexpected result"),
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::{Offset, OffsetValue, Result, Session};
+use super::command::LuaCommand;
+use super::message::MessageMode;
+use super::string;
+
+/// A token to obtain the value read from an offset
+/// Tokens are returned by `SafeSession::read()`, and they are used to fetch the read values
+/// from the `Results` returned by `SafeSession::process()`. They are also returned by
+/// `Prepared::read()` to fetch the values from the prepared session after each execution.
+#[derive(Debug)]
+pub struct ReadToken<T> {
+    session: usize,
+    index: usize,
+    _value: PhantomData<T>,
+}
+
+impl<T> ReadToken<T> {
+    pub(crate) fn new(session: usize, index: usize) -> Self {
+        ReadToken { session, index, _value: PhantomData }
+    }
+
+    /// The index of the token in the session it belongs to
+    /// It panics if the token was not obtained from the session with the given id.
+    pub(crate) fn index_in(&self, session: usize) -> usize {
+        assert_eq!(self.session, session, "read token does not belong to this session");
+        self.index
+    }
+}
+
+static NEXT_SESSION_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Obtain a new id for a session issuing read tokens
+pub(crate) fn next_session_id() -> usize {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The results of processing a `SafeSession`
+/// They are also returned by the async sessions of the `asynchronous` module.
+pub struct Results {
+    session: usize,
+    buffers: Vec<Vec<u8>>,
+    nbytes: usize,
+}
+
+impl Results {
+    pub(crate) fn new(session: usize, buffers: Vec<Vec<u8>>, nbytes: usize) -> Self {
+        Results { session, buffers, nbytes }
+    }
+
+    /// Obtain the value read for the given token
+    /// It panics if the token was not obtained from the session that produced these results.
+    pub fn get<T: OffsetValue>(&self, token: &ReadToken<T>) -> T {
+        T::decode_le(&self.buffers[token.index_in(self.session)])
+    }
+
+    /// Obtain the string read for the given token
+    /// The string ends at the first null byte. Non-ASCII bytes are replaced by `U+FFFD`. It
+    /// panics if the token was not obtained from the session that produced these results.
+    pub fn get_string(&self, token: &ReadToken<String>) -> String {
+        string::decode(&self.buffers[token.index_in(self.session)])
+    }
+
+    /// The number of bytes processed by the session
+    pub fn processed_bytes(&self) -> usize { self.nbytes }
+}
+
+/// A session that does not require raw pointers or references to destinations
+/// The read operations return a `ReadToken` instead of storing the value in a destination
+/// provided by the caller. The values are owned by the session, and they are returned in a
+/// `Results` object after processing.
+pub struct SafeSession<S: Session> {
+    id: usize,
+    session: S,
+    buffers: Vec<Vec<u8>>,
+}
+
+impl<S: Session> SafeSession<S> {
+    pub fn new(session: S) -> Self {
+        SafeSession { id: next_session_id(), session, buffers: Vec::new() }
+    }
+
+    /// The wrapped session
+    #[cfg(feature = "async")]
+    pub(crate) fn session_mut(&mut self) -> &mut S {
+        &mut self.session
+    }
+
+    /// Replace the wrapped session by the one returned by `f`, keeping the read buffers
+    /// The new session must read into the same destinations as the replaced one.
+    #[cfg(feature = "async")]
+    pub(crate) fn map_session<T: Session, F: FnOnce(S) -> T>(self, f: F) -> SafeSession<T> {
+        SafeSession { id: self.id, session: f(self.session), buffers: self.buffers }
+    }
+
+    /// Request to read from the given offset
+    /// The length of the offset is inferred from the type `T`.
+    pub fn read<T: OffsetValue>(&mut self, offset: u16) -> Result<ReadToken<T>> {
+        let index = self.buffers.len();
+        let mut buffer = vec![0; size_of::<T>()];
+        // The heap contents of the buffer do not move when the buffer is pushed into `buffers`,
+        // so the destination remains valid until the session is processed.
+        self.session.read_bytes(offset, buffer.as_mut_ptr(), buffer.len())?;
+        self.buffers.push(buffer);
+        Ok(ReadToken::new(self.id, index))
+    }
+
+    /// Request to write the given value into the given offset
+    /// The length of the offset is inferred from the type `T`.
+    pub fn write<T: OffsetValue>(&mut self, offset: u16, value: &T) -> Result<usize> {
+        self.session.write(offset, value)
+    }
+
+    /// Request to read a null-terminated string from an offset of `len` bytes
+    pub fn read_string(&mut self, offset: u16, len: usize) -> Result<ReadToken<String>> {
+        let index = self.buffers.len();
+        let mut buffer = vec![0; len];
+        self.session.read_bytes(offset, buffer.as_mut_ptr(), buffer.len())?;
+        self.buffers.push(buffer);
+        Ok(ReadToken::new(self.id, index))
+    }
+
+    /// Request to write the given ASCII string into an offset of `max_len` bytes
+    pub fn write_string(&mut self, offset: u16, max_len: usize, value: &str) -> Result<usize> {
+        self.session.write_string(offset, max_len, value)
+    }
+
+    /// Request to read from the given typed offset
+    pub fn read_offset<T: OffsetValue>(&mut self, offset: Offset<T>) -> Result<ReadToken<T>> {
+        self.read(offset.address())
+    }
+
+    /// Request to write the given value into the given typed offset
+    pub fn write_offset<T: OffsetValue>(&mut self, offset: Offset<T>, value: &T) -> Result<usize> {
+        self.write(offset.address(), value)
+    }
+
+    /// Request to send the given control to the simulator with the given parameter
+    pub fn send_control(&mut self, control: u32, param: i64) -> Result<usize> {
+        self.session.send_control(control, param)
+    }
+
+    /// Request to display the given message in the simulator window
+    pub fn display_message(&mut self, text: &str, duration: Duration, mode: MessageMode)
+        -> Result<usize>
+    {
+        self.session.display_message(text, duration, mode)
+    }
+
+    /// Request to clear the message displayed in the simulator window
+    pub fn clear_message(&mut self) -> Result<usize> {
+        self.session.clear_message()
+    }
+
+    /// Request to send the given command to the Lua plugin with the given name
+    pub fn lua(&mut self, name: &str, command: LuaCommand) -> Result<usize> {
+        self.session.lua(name, command)
+    }
+
+    /// Request to execute the given control of a macro file with the given parameter
+    pub fn execute_macro(&mut self, file: &str, control: &str, param: i32) -> Result<usize> {
+        self.session.execute_macro(file, control, param)
+    }
+
+    /// Process the requested operations, returning the read values
+    pub fn process(self) -> Result<Results> {
+        let nbytes = self.session.process()?;
+        Ok(Results::new(self.id, self.buffers, nbytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::{offsets, Handle};
+    use super::super::mock::MockHandle;
+
+    #[test]
+    fn should_obtain_read_values_by_token() {
+        let mut handle = MockHandle::new();
+        handle.set(0x3304, &0x49505553u32);
+        handle.poke(0x0238, &[12, 34, 56]);
+        let results = {
+            let mut session = handle.safe_session();
+            let fsuipc_ver = session.read::<u32>(0x3304).unwrap();
+            let hour = session.read::<u8>(0x0238).unwrap();
+            let minute = session.read::<u8>(0x0239).unwrap();
+            let second = session.read::<u8>(0x023a).unwrap();
+            let results = session.process().unwrap();
+            assert_eq!(results.get(&fsuipc_ver), 0x49505553);
+            assert_eq!(results.get(&hour), 12);
+            assert_eq!(results.get(&minute), 34);
+            assert_eq!(results.get(&second), 56);
+            results
+        };
+        assert_eq!(results.processed_bytes(), 4*16 + 4 + 1 + 1 + 1 + 4);
+    }
+
+    #[test]
+    fn should_write_values() {
+        let mut handle = MockHandle::new();
+        {
+            let mut session = handle.safe_session();
+            session.write(0x0330, &(1020u16 * 16)).unwrap();
+            let qnh = session.read::<u16>(0x0330).unwrap();
+            let results = session.process().unwrap();
+            assert_eq!(results.get(&qnh), 1020 * 16);
+        }
+        assert_eq!(handle.get::<u16>(0x0330), 1020 * 16);
+    }
+
+    #[test]
+    fn should_read_typed_offsets() {
+        let mut handle = MockHandle::new();
+        handle.set(offsets::FSUIPC_VERSION.address(), &0x49505553u32);
+        handle.set(offsets::FS_VERSION.address(), &12u16);
+        let mut session = handle.safe_session();
+        let fsuipc_ver = session.read_offset(offsets::FSUIPC_VERSION).unwrap();
+        let fs_ver = session.read_offset(offsets::FS_VERSION).unwrap();
+        let results = session.process().unwrap();
+        assert_eq!(results.get(&fsuipc_ver), 0x49505553);
+        assert_eq!(results.get(&fs_ver), 12);
+        assert_eq!(results.processed_bytes(), (16 + 4) + (16 + 2) + 4);
+    }
+
+    #[test]
+    fn should_read_and_write_strings() {
+        let mut handle = MockHandle::new();
+        handle.poke(0x3d00, b"Cessna Skyhawk\0");
+        {
+            let mut session = handle.safe_session();
+            let title = session.read_string(0x3d00, 256).unwrap();
+            session.write_string(0x313c, 12, "N172SP").unwrap();
+            let id = session.read_string(0x313c, 12).unwrap();
+            let results = session.process().unwrap();
+            assert_eq!(results.get_string(&title), "Cessna Skyhawk");
+            assert_eq!(results.get_string(&id), "N172SP");
+        }
+        assert_eq!(handle.peek(0x313c, 12), b"N172SP\0\0\0\0\0\0");
+    }
+
+    #[test]
+    #[should_panic]
+    fn should_panic_on_foreign_token() {
+        let mut handle = MockHandle::new();
+        let token = {
+            let mut session = handle.safe_session();
+            session.read::<u32>(0x3304).unwrap()
+        };
+        let mut session = handle.safe_session();
+        session.read::<u8>(0x0238).unwrap();
+        let results = session.process().unwrap();
+        results.get(&token);
+    }
+
+    #[test]
+    #[should_panic(expected = "read token does not belong to this session")]
+    fn should_panic_on_foreign_token_of_same_type() {
+        let mut handle = MockHandle::new();
+        let token = {
+            let mut session = handle.safe_session();
+            session.read::<u32>(0x3304).unwrap()
+        };
+        let mut session = handle.safe_session();
+        session.read::<u32>(0x3304).unwrap();
+        let results = session.process().unwrap();
+        results.get(&token);
+    }
+}
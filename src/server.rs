@@ -0,0 +1,199 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{Error, Result};
+use super::ipc::*;
+
+/// The code returned to clients when their requests were processed
+pub const FS6IPC_MESSAGE_SUCCESS: i64 = 1;
+
+/// The code returned to clients when their requests could not be processed
+pub const FS6IPC_MESSAGE_FAILURE: i64 = 0;
+
+/// A store of offsets served to FSUIPC clients
+/// This is the extension point of FSUIPC-compatible servers: the dispatcher decodes the
+/// requests of the clients and delegates the actual reads and writes to the store.
+pub trait OffsetStore {
+    /// Read the bytes of the given offset into `data`
+    fn read(&mut self, offset: u16, data: &mut [u8]) -> Result<()>;
+
+    /// Write the bytes of `data` into the given offset
+    fn write(&mut self, offset: u16, data: &[u8]) -> Result<()>;
+}
+
+impl<S: OffsetStore + ?Sized> OffsetStore for &mut S {
+    fn read(&mut self, offset: u16, data: &mut [u8]) -> Result<()> {
+        (**self).read(offset, data)
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) -> Result<()> {
+        (**self).write(offset, data)
+    }
+}
+
+/// A byte slice is a store whose offsets are the indices of the slice
+/// Requests out of the slice are rejected as FSUIPC does with invalid requests.
+impl OffsetStore for [u8] {
+    fn read(&mut self, offset: u16, data: &mut [u8]) -> Result<()> {
+        let begin = offset as usize;
+        let src = self.get(begin..begin + data.len())
+            .ok_or(Error::Rejected { code: FS6IPC_MESSAGE_FAILURE })?;
+        data.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u16, data: &[u8]) -> Result<()> {
+        let begin = offset as usize;
+        let dest = self.get_mut(begin..begin + data.len())
+            .ok_or(Error::Rejected { code: FS6IPC_MESSAGE_FAILURE })?;
+        dest.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A dispatcher of the requests sent by FSUIPC clients
+/// It processes the buffers of the FS6IPC protocol as FSUIPC module does: write requests are
+/// applied to the store and the bodies of read requests are filled in place with the contents
+/// of the store, in order, until the termination mark is found. The buffers are then sent
+/// back to the clients, which decode them as any other FSUIPC response.
+pub struct Dispatcher<S> {
+    store: S,
+}
+
+impl<S: OffsetStore> Dispatcher<S> {
+    /// Create a new dispatcher serving the offsets of the given store
+    pub fn new(store: S) -> Self {
+        Dispatcher { store }
+    }
+
+    /// The store whose offsets are served
+    pub fn store(&self) -> &S { &self.store }
+
+    /// The store whose offsets are served, for mutable access
+    pub fn store_mut(&mut self) -> &mut S { &mut self.store }
+
+    /// Consume the dispatcher, returning its store
+    pub fn into_inner(self) -> S { self.store }
+
+    /// Process the requests in the given buffer
+    /// The buffer must not include the prefix of the handle that sent it. The requests before
+    /// a failed one are kept applied, as FSUIPC module does.
+    pub fn process(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let mut start = 0;
+        loop {
+            let (header, range) = read_message(buffer, start)?;
+            start = range.end;
+            let body = &mut buffer[range];
+            match header {
+                MsgHeader::ReadStateData { offset, .. } => self.store.read(offset, body)?,
+                MsgHeader::WriteStateData { offset, .. } => self.store.write(offset, body)?,
+                MsgHeader::TerminationMark => return Ok(()),
+            }
+        }
+    }
+
+    /// Process the requests in the given buffer, returning the code expected by the client
+    /// This is the result to be returned to the IPC message sent by the client.
+    pub fn dispatch(&mut self, buffer: &mut [u8]) -> i64 {
+        match self.process(buffer) {
+            Ok(()) => FS6IPC_MESSAGE_SUCCESS,
+            Err(_) => FS6IPC_MESSAGE_FAILURE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::collections::HashMap;
+    use std::ptr;
+
+    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+    use super::*;
+    use super::super::batch::Batch;
+
+    /// A store of a few named offsets, as an alternative simulator would have
+    #[derive(Default)]
+    struct Sim {
+        offsets: HashMap<u16, u32>,
+    }
+
+    impl OffsetStore for Sim {
+        fn read(&mut self, offset: u16, data: &mut [u8]) -> Result<()> {
+            let value = self.offsets.get(&offset).ok_or(Error::Rejected { code: 0 })?;
+            (&mut data[..]).write_u32::<LittleEndian>(*value)?;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u16, mut data: &[u8]) -> Result<()> {
+            self.offsets.insert(offset, data.read_u32::<LittleEndian>()?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_serve_requests_encoded_by_clients() {
+        let mut dispatcher = Dispatcher::new(Sim::default());
+        dispatcher.store_mut().offsets.insert(0x3304, 0x49505553);
+        let mut version = 0u32;
+        let mut altitude = 0u32;
+        let mut batch = Batch::new(1024);
+        batch.read(0x3304, &mut version as *mut u32 as *mut u8, 4).unwrap();
+        batch.write(0x0570, &1500u32 as *const u32 as *const u8, 4).unwrap();
+        batch.read(0x0570, &mut altitude as *mut u32 as *mut u8, 4).unwrap();
+        unsafe { batch.process(0, |buffer| dispatcher.process(buffer)).unwrap() };
+        assert_eq!(version, 0x49505553);
+        assert_eq!(altitude, 1500);
+        assert_eq!(dispatcher.store().offsets[&0x0570], 1500);
+    }
+
+    #[test]
+    fn should_return_failure_code_for_invalid_requests() {
+        let mut memory = [0u8; 16];
+        let mut dispatcher = Dispatcher::new(&mut memory[..]);
+        let mut buffer = Vec::new();
+        buffer.write_rsd(0x000e, ptr::null_mut(), 4).unwrap();
+        buffer.write_header(&MsgHeader::TerminationMark).unwrap();
+        assert_eq!(dispatcher.dispatch(&mut buffer), FS6IPC_MESSAGE_FAILURE);
+
+        let mut buffer = Vec::new();
+        buffer.write_header(&MsgHeader::WriteStateData { offset: 0, len: 8 }).unwrap();
+        buffer.extend_from_slice(&[1, 2, 3, 4]);
+        match dispatcher.process(&mut buffer) {
+            Err(Error::TruncatedMessage { offset_in_buffer: 0, required: 20, available: 16 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut buffer = Vec::new();
+        buffer.write_header(&MsgHeader::WriteStateData { offset: 0, len: 0xffff_fff8 }).unwrap();
+        buffer.extend_from_slice(&[1, 2, 3, 4]);
+        match dispatcher.process(&mut buffer) {
+            Err(Error::TruncatedMessage { offset_in_buffer: 0, available: 16, .. }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_apply_requests_before_failure() {
+        let mut memory = [0u8; 16];
+        {
+            let mut dispatcher = Dispatcher::new(&mut memory[..]);
+            let mut buffer = Vec::new();
+            buffer.write_wsd(0x0004, [7u8, 8].as_ptr(), 2).unwrap();
+            buffer.write_wsd(0x000f, [9u8, 9].as_ptr(), 2).unwrap();
+            buffer.write_header(&MsgHeader::TerminationMark).unwrap();
+            match dispatcher.process(&mut buffer) {
+                Err(Error::Rejected { code: FS6IPC_MESSAGE_FAILURE }) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(&memory[4..6], &[7, 8]);
+        assert_eq!(memory[15], 0);
+    }
+}
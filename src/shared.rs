@@ -0,0 +1,151 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::{BatchSession, Exchange, Handle, HandleRef, Result};
+
+/// A handle that can be shared among threads
+/// It wraps another handle, which is used by one session at a time. Cloning a shared handle
+/// returns another reference to the same wrapped handle, so each thread can build its own
+/// sessions concurrently. The sessions are serialised when they are processed, so the requests
+/// of a session are never interleaved with the ones of another.
+pub struct SharedHandle<E> {
+    inner: Arc<Mutex<E>>,
+    buffer_len: usize,
+    prefix_len: usize,
+}
+
+impl<E: Exchange> SharedHandle<E> {
+    /// Create a new shared handle wrapping the given one
+    pub fn new(handle: E) -> Self {
+        SharedHandle {
+            buffer_len: handle.buffer_len(),
+            prefix_len: handle.prefix_len(),
+            inner: Arc::new(Mutex::new(handle)),
+        }
+    }
+
+    /// Lock the wrapped handle for exclusive access
+    /// Sessions of other threads are blocked until the returned guard is dropped. If a thread
+    /// panicked while holding the lock, the handle is returned anyway, so it may be left in the
+    /// middle of an exchange.
+    pub fn lock(&self) -> MutexGuard<'_, E> {
+        // A panic within an exchange may leave stateful handles half-way (e.g., a partial
+        // record of a `RecordingHandle`, or a `ReconnectingHandle` connected to a handle that
+        // failed). The lock is recovered anyway, so a single panic does not fail the sessions
+        // of every other thread, and the next exchanges fail or recover as the handle does.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Create a new session from this handle
+    /// Unlike `Handle::session()`, it does not require exclusive access to the handle.
+    pub fn session(&self) -> SharedSession<E> {
+        BatchSession::new(self.clone(), self.buffer_len)
+    }
+}
+
+impl<E> Clone for SharedHandle<E> {
+    fn clone(&self) -> Self {
+        SharedHandle {
+            inner: self.inner.clone(),
+            buffer_len: self.buffer_len,
+            prefix_len: self.prefix_len,
+        }
+    }
+}
+
+impl<E: Exchange> Exchange for SharedHandle<E> {
+    fn prefix_len(&self) -> usize { self.prefix_len }
+
+    fn buffer_len(&self) -> usize { self.buffer_len }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.lock().exchange(buffer)
+    }
+}
+
+impl<'a, E: Exchange> Handle<'a> for SharedHandle<E> {
+    type Sess = SharedSession<E>;
+
+    fn session(&'a mut self) -> SharedSession<E> {
+        SharedHandle::session(self)
+    }
+}
+
+/// A session of a `SharedHandle`
+pub type SharedSession<E> = BatchSession<SharedHandle<E>>;
+
+impl<E: Exchange> HandleRef for SharedHandle<E> {
+    type Target = E;
+
+    fn with_handle<R, F: FnOnce(&mut E) -> R>(&mut self, f: F) -> R {
+        // The lock is held for all the exchanges of the session, so the sessions of other
+        // threads are not interleaved when the requests are split.
+        f(&mut self.lock())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::thread;
+
+    use super::*;
+    use super::super::Session;
+    use super::super::mock::MockHandle;
+
+    #[test]
+    fn should_process_sessions_from_many_threads() {
+        let handle = SharedHandle::new(MockHandle::new());
+        let threads: Vec<_> = (0..8u16).map(|i| {
+            let handle = handle.clone();
+            thread::spawn(move || {
+                for n in 0..100u32 {
+                    let mut value = 0u32;
+                    let mut session = handle.session();
+                    session.write(0x1000 + i * 4, &n).unwrap();
+                    session.read(0x1000 + i * 4, &mut value).unwrap();
+                    session.process().unwrap();
+                    assert_eq!(value, n);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let mock = handle.lock();
+        assert_eq!(mock.exchanges(), 800);
+        for i in 0..8u16 {
+            assert_eq!(mock.get::<u32>(0x1000 + i * 4), 99);
+        }
+    }
+
+    #[test]
+    fn should_not_interleave_split_sessions() {
+        let handle = SharedHandle::new(MockHandle::with_buffer_len(64));
+        let threads: Vec<_> = (0..4u8).map(|i| {
+            let handle = handle.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let mut value = [0u8; 8];
+                    let mut session = handle.session();
+                    session.set_auto_split(true);
+                    session.write(0x2000, &[i; 8]).unwrap();
+                    session.write(0x2010, &[0u8; 32]).unwrap();
+                    session.read(0x2000, &mut value).unwrap();
+                    session.process().unwrap();
+                    assert_eq!(value, [i; 8]);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use super::{Error, Result};
+
+/// Decode a null-terminated string from the bytes of an offset
+/// The string ends at the first null byte, or at the end of the bytes if there is none.
+/// Non-ASCII bytes are replaced by `U+FFFD`, since the encoding used by the simulator is
+/// unknown.
+pub fn decode(bytes: &[u8]) -> String {
+    bytes.iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| if b.is_ascii() { b as char } else { '\u{FFFD}' })
+        .collect()
+}
+
+/// Encode an ASCII string into `max_len` bytes padded with nulls
+/// The string must leave room for the null terminator.
+pub fn encode(value: &str, max_len: usize) -> Result<Vec<u8>> {
+    if !value.is_ascii() {
+        return Err(Error::NonAsciiString);
+    }
+    if value.len() >= max_len {
+        return Err(Error::StringTooLong { len: value.len(), max_len });
+    }
+    let mut bytes = vec![0; max_len];
+    bytes[..value.len()].copy_from_slice(value.as_bytes());
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn should_decode_until_null() {
+        assert_eq!(decode(b"Cessna 172\0garbage"), "Cessna 172");
+        assert_eq!(decode(b"no terminator"), "no terminator");
+        assert_eq!(decode(b"\0"), "");
+        assert_eq!(decode(b"caf\xe9\0"), "caf\u{FFFD}");
+    }
+
+    #[test]
+    fn should_encode_padded_with_nulls() {
+        assert_eq!(encode("IBE", 6).unwrap(), b"IBE\0\0\0");
+        assert_eq!(encode("", 1).unwrap(), b"\0");
+    }
+
+    #[test]
+    fn should_fail_to_encode_invalid_strings() {
+        match encode("TOOLONG", 7) {
+            Err(Error::StringTooLong { len: 7, max_len: 7 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match encode("café", 16) {
+            Err(Error::NonAsciiString) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::ops::Range;
+
+use super::{Error, Result};
+
+const FEET_PER_METRE: f64 = 1.0 / 0.3048;
+const FEET_PER_MINUTE_PER_METRE_PER_SECOND: f64 = 60.0 * FEET_PER_METRE;
+const HPA_PER_INHG: f64 = 33.863_886_666_667;
+
+const LATITUDE_SCALE: f64 = 10_001_750.0 * 65536.0 * 65536.0 / 90.0;
+const LONGITUDE_SCALE: f64 = 65536.0 * 65536.0 * 65536.0 * 65536.0 / 360.0;
+const ANGLE_SCALE: f64 = 65536.0 * 65536.0 / 360.0;
+const ALTITUDE_SCALE: f64 = 65536.0 * 65536.0;
+const AIRSPEED_SCALE: f64 = 128.0;
+const VERTICAL_SPEED_SCALE: f64 = 256.0;
+const PRESSURE_SCALE: f64 = 16.0;
+
+const I64_RANGE: Range<f64> = i64::MIN as f64..-(i64::MIN as f64);
+const I32_RANGE: Range<f64> = i32::MIN as f64..-(i32::MIN as f64);
+const U32_RANGE: Range<f64> = 0.0..u32::MAX as f64 + 1.0;
+const U16_RANGE: Range<f64> = 0.0..u16::MAX as f64 + 1.0;
+
+/// Scale the given value by `factor` into a raw value within the given range
+/// It fails with `Error::OutOfRange` if the raw value does not fit, or if it is not a number.
+fn scale(value: f64, factor: f64, range: Range<f64>) -> Result<f64> {
+    let raw = (value * factor).round();
+    if !range.contains(&raw) {
+        return Err(Error::OutOfRange { value });
+    }
+    Ok(raw)
+}
+
+offset_struct! {
+    /// A latitude, as encoded in offset 0x0560
+    /// The raw value is degrees * 10001750 * 65536 * 65536 / 90, positive to the north.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Latitude {
+        pub raw: i64,
+    }
+}
+
+impl Latitude {
+    /// Encode the given latitude, which must be between -90 and 90 degrees
+    pub fn from_degrees(degrees: f64) -> Result<Self> {
+        if !(-90.0..=90.0).contains(&degrees) {
+            return Err(Error::OutOfRange { value: degrees });
+        }
+        Ok(Latitude { raw: scale(degrees, LATITUDE_SCALE, I64_RANGE)? as i64 })
+    }
+
+    pub fn degrees(&self) -> f64 { self.raw as f64 / LATITUDE_SCALE }
+}
+
+offset_struct! {
+    /// A longitude, as encoded in offset 0x0568
+    /// The raw value is degrees * 65536 * 65536 * 65536 * 65536 / 360, positive to the east.
+    /// The raw value of 180 degrees does not fit, so it is encoded as -180 degrees.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Longitude {
+        pub raw: i64,
+    }
+}
+
+impl Longitude {
+    /// Encode the given longitude, which must be between -180 and 180 degrees
+    pub fn from_degrees(degrees: f64) -> Result<Self> {
+        let degrees = if degrees == 180.0 { -180.0 } else { degrees };
+        Ok(Longitude { raw: scale(degrees, LONGITUDE_SCALE, I64_RANGE)? as i64 })
+    }
+
+    pub fn degrees(&self) -> f64 { self.raw as f64 / LONGITUDE_SCALE }
+}
+
+offset_struct! {
+    /// An altitude above sea level, as encoded in offset 0x0570
+    /// The raw value is metres * 65536 * 65536.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Altitude {
+        pub raw: i64,
+    }
+}
+
+impl Altitude {
+    pub fn from_metres(metres: f64) -> Result<Self> {
+        Ok(Altitude { raw: scale(metres, ALTITUDE_SCALE, I64_RANGE)? as i64 })
+    }
+
+    pub fn from_feet(feet: f64) -> Result<Self> {
+        Ok(Altitude { raw: scale(feet, ALTITUDE_SCALE / FEET_PER_METRE, I64_RANGE)? as i64 })
+    }
+
+    pub fn metres(&self) -> f64 { self.raw as f64 / ALTITUDE_SCALE }
+
+    pub fn feet(&self) -> f64 { self.metres() * FEET_PER_METRE }
+}
+
+offset_struct! {
+    /// A heading, as encoded in offset 0x0580
+    /// The raw value is degrees * 65536 * 65536 / 360, wrapping at 360 degrees.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Heading {
+        pub raw: u32,
+    }
+}
+
+impl Heading {
+    /// Encode the given heading, normalized into the range [0, 360)
+    /// It fails with `Error::OutOfRange` if the heading is not finite.
+    pub fn from_degrees(degrees: f64) -> Result<Self> {
+        if !degrees.is_finite() {
+            return Err(Error::OutOfRange { value: degrees });
+        }
+        // A heading rounded up to 360 degrees wraps to 0.
+        let raw = (degrees.rem_euclid(360.0) * ANGLE_SCALE).round() as u64;
+        Ok(Heading { raw: raw as u32 })
+    }
+
+    pub fn degrees(&self) -> f64 { self.raw as f64 / ANGLE_SCALE }
+}
+
+offset_struct! {
+    /// A pitch or bank angle, as encoded in offsets 0x0578 and 0x057C
+    /// The raw value is degrees * 65536 * 65536 / 360.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Angle {
+        pub raw: i32,
+    }
+}
+
+impl Angle {
+    /// Encode the given angle, which must be in the range [-180, 180)
+    pub fn from_degrees(degrees: f64) -> Result<Self> {
+        Ok(Angle { raw: scale(degrees, ANGLE_SCALE, I32_RANGE)? as i32 })
+    }
+
+    pub fn degrees(&self) -> f64 { self.raw as f64 / ANGLE_SCALE }
+}
+
+offset_struct! {
+    /// An air speed, as encoded in offsets 0x02B8 and 0x02BC
+    /// The raw value is knots * 128.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Airspeed {
+        pub raw: u32,
+    }
+}
+
+impl Airspeed {
+    /// Encode the given air speed, which must not be negative
+    pub fn from_knots(knots: f64) -> Result<Self> {
+        Ok(Airspeed { raw: scale(knots, AIRSPEED_SCALE, U32_RANGE)? as u32 })
+    }
+
+    pub fn knots(&self) -> f64 { self.raw as f64 / AIRSPEED_SCALE }
+}
+
+offset_struct! {
+    /// A vertical speed, as encoded in offset 0x02C8
+    /// The raw value is metres per second * 256, positive when climbing.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct VerticalSpeed {
+        pub raw: i32,
+    }
+}
+
+impl VerticalSpeed {
+    pub fn from_feet_per_minute(fpm: f64) -> Result<Self> {
+        let factor = VERTICAL_SPEED_SCALE / FEET_PER_MINUTE_PER_METRE_PER_SECOND;
+        Ok(VerticalSpeed { raw: scale(fpm, factor, I32_RANGE)? as i32 })
+    }
+
+    pub fn feet_per_minute(&self) -> f64 {
+        self.raw as f64 / VERTICAL_SPEED_SCALE * FEET_PER_MINUTE_PER_METRE_PER_SECOND
+    }
+}
+
+offset_struct! {
+    /// A barometric pressure, as encoded in offset 0x0330
+    /// The raw value is hectopascals (millibars) * 16.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Pressure {
+        pub raw: u16,
+    }
+}
+
+impl Pressure {
+    /// Encode the given pressure, which must be between 0 and 4095 hPa
+    pub fn from_hpa(hpa: f64) -> Result<Self> {
+        Ok(Pressure { raw: scale(hpa, PRESSURE_SCALE, U16_RANGE)? as u16 })
+    }
+
+    pub fn from_inhg(inhg: f64) -> Result<Self> {
+        Ok(Pressure { raw: scale(inhg, PRESSURE_SCALE * HPA_PER_INHG, U16_RANGE)? as u16 })
+    }
+
+    pub fn hpa(&self) -> f64 { self.raw as f64 / PRESSURE_SCALE }
+
+    pub fn inhg(&self) -> f64 { self.hpa() / HPA_PER_INHG }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn should_convert_position() {
+        assert_close(Latitude { raw: 42957189152768000 }.degrees(), 90.0);
+        assert_eq!(Latitude::from_degrees(-45.0).unwrap().raw, -21478594576384000);
+        assert_close(Longitude { raw: 1 << 62 }.degrees(), 90.0);
+        assert_eq!(Longitude::from_degrees(-90.0).unwrap().raw, -(1 << 62));
+        assert_close(Altitude { raw: 1000 << 32 }.metres(), 1000.0);
+        assert_close(Altitude { raw: 1000 << 32 }.feet(), 3280.839895);
+        assert_close(Altitude::from_feet(1000.0).unwrap().metres(), 304.8);
+        assert_eq!(Altitude::from_metres(1000.0).unwrap().raw, 1000 << 32);
+        assert_eq!(Longitude::from_degrees(180.0).unwrap().raw, i64::MIN);
+    }
+
+    #[test]
+    fn should_convert_angles() {
+        assert_close(Heading { raw: 0x40000000 }.degrees(), 90.0);
+        assert_eq!(Heading::from_degrees(270.0).unwrap().raw, 0xc0000000);
+        assert_eq!(Heading::from_degrees(-90.0).unwrap().raw, 0xc0000000);
+        assert_eq!(Heading::from_degrees(360.0).unwrap().raw, 0);
+        assert_close(Angle { raw: -0x40000000 }.degrees(), -90.0);
+        assert_eq!(Angle::from_degrees(45.0).unwrap().raw, 0x20000000);
+    }
+
+    #[test]
+    fn should_convert_speeds() {
+        assert_close(Airspeed { raw: 32000 }.knots(), 250.0);
+        assert_eq!(Airspeed::from_knots(250.0).unwrap().raw, 32000);
+        assert_close(VerticalSpeed { raw: 256 }.feet_per_minute(), 196.850394);
+        assert_eq!(VerticalSpeed::from_feet_per_minute(-196.850394).unwrap().raw, -256);
+    }
+
+    #[test]
+    fn should_convert_pressure() {
+        assert_close(Pressure { raw: 1020 * 16 }.hpa(), 1020.0);
+        assert_eq!(Pressure::from_hpa(1013.25).unwrap().raw, 16212);
+        assert_eq!(Pressure::from_inhg(29.92).unwrap().raw, 16211);
+        assert_close(Pressure { raw: 16212 }.inhg(), 29.921255);
+    }
+
+    fn assert_out_of_range<T: ::std::fmt::Debug>(result: Result<T>, expected: f64) {
+        match result {
+            Err(Error::OutOfRange { value }) if value == expected || value.is_nan() => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_fail_to_encode_values_out_of_range() {
+        assert_out_of_range(Latitude::from_degrees(90.5), 90.5);
+        assert_out_of_range(Longitude::from_degrees(-180.5), -180.5);
+        assert_out_of_range(Altitude::from_metres(1e10), 1e10);
+        assert_out_of_range(Heading::from_degrees(f64::INFINITY), f64::INFINITY);
+        assert_out_of_range(Angle::from_degrees(180.0), 180.0);
+        assert_out_of_range(Airspeed::from_knots(-10.0), -10.0);
+        assert_out_of_range(VerticalSpeed::from_feet_per_minute(1e10), 1e10);
+        assert_out_of_range(Pressure::from_hpa(5000.0), 5000.0);
+        assert_out_of_range(Pressure::from_inhg(-1.0), -1.0);
+        assert_out_of_range(Airspeed::from_knots(f64::NAN), f64::NAN);
+    }
+}
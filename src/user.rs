@@ -7,9 +7,10 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::ffi::CString;
-use std::io;
+use std::io::{Read, Write};
 use std::os::raw::c_void;
 use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use kernel32::*;
 use user32::{FindWindowExA, RegisterWindowMessageA, SendMessageA};
@@ -19,7 +20,7 @@ use winapi::minwindef::ATOM;
 use winapi::windef::HWND;
 use winapi::winnt::{HANDLE, PAGE_READWRITE};
 
-use super::{Handle, Session};
+use super::{BatchSession, Error, Exchange, Handle, Result};
 use super::ipc::*;
 use super::raw::{MutRawBytes, RawBytes};
 
@@ -31,8 +32,13 @@ pub struct UserHandle {
     data: *mut u8,
 }
 
+unsafe impl Send for UserHandle {}
+
 impl UserHandle {
-    pub fn new() -> io::Result<Self> {
+    /// Create a new handle to FSUIPC
+    /// It fails with `Error::NotRunning` if the window of FSUIPC is not found, even if the
+    /// simulator is running without FSUIPC.
+    pub fn new() -> Result<Self> {
         unsafe {
             let win_name = CString::new("UIPCMAIN").unwrap();
             let handle = FindWindowExA(
@@ -40,17 +46,13 @@ impl UserHandle {
                 ptr::null_mut(),
                 win_name.as_ptr(),
                 ptr::null_mut());
-            if handle == ptr::null_mut() {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    "cannot connect to user FSUIPC: cannot create window handle"));
+            if handle.is_null() {
+                return Err(Error::NotRunning);
             }
             let msg_name = CString::new("FsasmLib:IPC").unwrap();
             let msg_id = RegisterWindowMessageA(msg_name.as_ptr());
             if msg_id == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    "cannot connect to user FSUIPC: cannot register window message"));
+                return Err(Error::RegisterMessageFailed);
             }
 
             let file_mapping_name = CString::new(
@@ -60,9 +62,7 @@ impl UserHandle {
 
             let file_mapping_atom = GlobalAddAtomA(file_mapping_name.as_ptr());
             if file_mapping_atom == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    "cannot connect to user FSUIPC: cannot add global atom"));
+                return Err(Error::AtomCreationFailed);
             }
 
             let file_mapping = CreateFileMappingA(
@@ -71,39 +71,53 @@ impl UserHandle {
                 PAGE_READWRITE,
                 0, FILE_MAPPING_LEN as u32,
                 file_mapping_name.as_ptr());
-            if file_mapping == ptr::null_mut() {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    "cannot connect to user FSUIPC: cannot create file mapping"));
+            if file_mapping.is_null() {
+                return Err(Error::MappingFailed);
             }
             let data = MapViewOfFile(file_mapping, FILE_MAP_WRITE, 0, 0, 0) as *mut u8;
-            if data == ptr::null_mut() {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionRefused,
-                    "cannot connect to user FSUIPC: cannot map view of file"));
+            if data.is_null() {
+                return Err(Error::MappingFailed);
             }
             Ok(UserHandle {
-                handle: handle,
-                file_mapping_atom: file_mapping_atom,
-                file_mapping: file_mapping,
-                msg_id: msg_id,
-                data: data,
+                handle,
+                file_mapping_atom,
+                file_mapping,
+                msg_id,
+                data,
             })
         }
     }
 }
 
+impl Exchange for UserHandle {
+    fn buffer_len(&self) -> usize { FILE_MAPPING_LEN }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        if buffer.len() > FILE_MAPPING_LEN {
+            return Err(Error::BufferOverflow { required: buffer.len(), available: FILE_MAPPING_LEN });
+        }
+        unsafe {
+            MutRawBytes::new(self.data, FILE_MAPPING_LEN).write_all(buffer)?;
+            let send_result = SendMessageA(
+                self.handle,
+                self.msg_id,
+                self.file_mapping_atom as WinUInt,
+                0);
+            if send_result != FS6IPC_MESSAGE_SUCCESS {
+                return Err(Error::Rejected { code: send_result as i64 });
+            }
+        }
+        RawBytes::new(self.data, buffer.len()).read_exact(buffer)?;
+        Ok(())
+    }
+}
+
 impl<'a> Handle<'a> for UserHandle {
     type Sess = UserSession<'a>;
 
     fn session(&'a mut self) -> UserSession<'a> {
-        let data = self.data;
-        UserSession {
-            handle: self,
-            buffer: MutRawBytes::new(data, FILE_MAPPING_LEN),
-            #[cfg(target_pointer_width = "64")]
-            destinations: Vec::new(),
-        }
+        let buffer_len = self.buffer_len();
+        BatchSession::new(self, buffer_len)
     }
 }
 
@@ -117,82 +131,14 @@ impl Drop for UserHandle {
     }
 }
 
-pub struct UserSession<'a> {
-    handle: &'a mut UserHandle,
-    buffer: MutRawBytes,
-    #[cfg(target_pointer_width = "64")]
-    destinations: Vec<*mut u8>,
-}
-
-impl<'a> Session for UserSession<'a> {
-    fn read_bytes(&mut self, offset: u16, dest: *mut u8, len: usize) -> io::Result<usize> {
-        #[cfg(target_pointer_width = "64")]
-        {
-            let idx = self.destinations.len();
-            self.destinations.push(dest);
-            self.buffer.write_rsd(offset, idx as *mut u8, len)
-        }
-        #[cfg(not(target_pointer_width = "64"))]
-        {
-            self.buffer.write_rsd(offset, dest, len)
-        }
-    }
-
-    fn write_bytes(&mut self, offset: u16, src: *const u8, len: usize) -> io::Result<usize> {
-        self.buffer.write_wsd(offset, src, len)
-    }
-
-    fn process(mut self) -> io::Result<usize> {
-        unsafe {
-            self.buffer.write_header(&MsgHeader::TerminationMark)?;
-            let send_result = SendMessageA(
-                self.handle.handle,
-                self.handle.msg_id,
-                self.handle.file_mapping_atom as WinUInt,
-                0);
-            if send_result != FS6IPC_MESSAGE_SUCCESS {
-                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
-                    "FSUIPC rejected the requests with error {}; possible buffer corruption",
-                    send_result)));
-            }
-            let mut buffer = RawBytes::new(self.handle.data, FILE_MAPPING_LEN);
-            loop {
-                let header = buffer.read_header()?;
-                match &header {
-                    &MsgHeader::ReadStateData { offset: _, len, target } => {
-                        #[cfg(target_pointer_width = "64")]
-                        let actual = {
-                            let idx = target as usize;
-                            *self.destinations.get(idx).ok_or_else(|| {
-                                io::Error::new(io::ErrorKind::InvalidData, "invalid destination index")
-                            })?
-                        };
-                        #[cfg(target_pointer_width = "64")]
-                        let mut output = MutRawBytes::new(actual, len);
-                        #[cfg(not(target_pointer_width = "64"))]
-                        let mut output = MutRawBytes::new(target, len);
-                        buffer.read_body(&header, &mut output)?;
-                    },
-                    &MsgHeader::WriteStateData { offset: _, len: _ } => {
-                        let mut output = io::sink();
-                        buffer.read_body(&header, &mut output)?;
-                    },
-                    &MsgHeader::TerminationMark => return Ok(buffer.consumed()),
-                }
-            }
-        }
-    }
-}
+/// A session of a `UserHandle`
+pub type UserSession<'a> = BatchSession<&'a mut UserHandle>;
 
 fn next_file_mapping_index() -> u32 {
-    unsafe {
-        let next = FILE_MAPPING_INDEX;
-        FILE_MAPPING_INDEX += 1;
-        next
-    }
+    FILE_MAPPING_INDEX.fetch_add(1, Ordering::SeqCst)
 }
 
 const FS6IPC_MESSAGE_SUCCESS: WinInt = 1;
 const FILE_MAPPING_LEN: usize = 64*1024;
 
-static mut FILE_MAPPING_INDEX: u32 = 0;
+static FILE_MAPPING_INDEX: AtomicU32 = AtomicU32::new(0);
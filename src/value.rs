@@ -0,0 +1,202 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::mem::size_of;
+use std::ptr;
+use std::slice;
+
+/// A value that can be read from or written to FSUIPC offsets
+/// FSUIPC offsets contain plain-old-data encoded in little-endian. This trait is implemented for
+/// integers, floats and fixed-size arrays of them. It can be implemented for `#[repr(C)]`
+/// structs by declaring them with `offset_struct!` macro.
+///
+/// # Safety
+///
+/// This trait is unsafe to implement because sessions copy the raw bytes of the offset into
+/// the memory of the value. The implementor must guarantee that the type has no padding, that
+/// any bit pattern is a valid value and that its memory representation in little-endian
+/// platforms is the one produced by `encode_le()`.
+pub unsafe trait OffsetValue : Copy {
+    /// Decode the value from the given little-endian bytes
+    /// The length of `bytes` must be the size of the value.
+    fn decode_le(bytes: &[u8]) -> Self;
+
+    /// Encode the value into the given bytes in little-endian
+    /// The length of `bytes` must be the size of the value.
+    fn encode_le(&self, bytes: &mut [u8]);
+}
+
+macro_rules! impl_offset_value_for_number {
+    ($($t:ty),*) => {
+        $(
+            unsafe impl OffsetValue for $t {
+                fn decode_le(bytes: &[u8]) -> Self {
+                    let mut array = [0u8; size_of::<$t>()];
+                    array.copy_from_slice(bytes);
+                    <$t>::from_le_bytes(array)
+                }
+
+                fn encode_le(&self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&<$t>::to_le_bytes(*self));
+                }
+            }
+        )*
+    }
+}
+
+impl_offset_value_for_number!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+unsafe impl<T: OffsetValue, const N: usize> OffsetValue for [T; N] {
+    fn decode_le(bytes: &[u8]) -> Self {
+        let len = size_of::<T>();
+        ::std::array::from_fn(|i| T::decode_le(&bytes[i * len..(i + 1) * len]))
+    }
+
+    fn encode_le(&self, bytes: &mut [u8]) {
+        let len = size_of::<T>();
+        for (i, item) in self.iter().enumerate() {
+            item.encode_le(&mut bytes[i * len..(i + 1) * len]);
+        }
+    }
+}
+
+/// A function decoding in place the little-endian bytes read into a destination
+/// It is called by sessions once the raw bytes of the offset are copied into the destination.
+pub type Decoder = unsafe fn(*mut u8);
+
+/// Decode in place the little-endian bytes of a value of type `T` read into `dest`
+/// This is unsafe since `dest` must point to a valid value of type `T`.
+pub(crate) unsafe fn decode_in_place<T: OffsetValue>(dest: *mut u8) {
+    let value = T::decode_le(slice::from_raw_parts(dest, size_of::<T>()));
+    ptr::write(dest as *mut T, value);
+}
+
+/// Declare a `#[repr(C)]` struct that implements `OffsetValue`
+/// The struct is declared with the given attributes and fields, and it derives `Clone` and
+/// `Copy`. Its fields must implement `OffsetValue` and they are encoded one after another
+/// in the order they are declared. A compilation error is raised if the struct has padding.
+///
+/// ```
+/// #[macro_use]
+/// extern crate fsuipc;
+///
+/// offset_struct! {
+///     #[derive(Debug, PartialEq)]
+///     pub struct LocalTime {
+///         pub hour: u8,
+///         pub minute: u8,
+///         pub second: u8,
+///     }
+/// }
+/// # fn main() {}
+/// ```
+#[macro_export]
+macro_rules! offset_struct {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($fvis:vis $field:ident : $ftype:ty),* $(,)*
+        }
+    ) => {
+        $(#[$attr])*
+        #[repr(C)]
+        #[derive(Clone, Copy)]
+        $vis struct $name {
+            $($fvis $field: $ftype),*
+        }
+
+        const _: () = assert!(
+            ::std::mem::size_of::<$name>() == 0 $(+ ::std::mem::size_of::<$ftype>())*,
+            "offset structs must not have padding");
+
+        unsafe impl $crate::OffsetValue for $name {
+            #[allow(unused_assignments)]
+            fn decode_le(bytes: &[u8]) -> Self {
+                let mut pos = 0;
+                $(
+                    let len = ::std::mem::size_of::<$ftype>();
+                    let $field = <$ftype as $crate::OffsetValue>::decode_le(
+                        &bytes[pos..pos + len]);
+                    pos += len;
+                )*
+                $name { $($field),* }
+            }
+
+            #[allow(unused_assignments)]
+            fn encode_le(&self, bytes: &mut [u8]) {
+                let mut pos = 0;
+                $(
+                    let len = ::std::mem::size_of::<$ftype>();
+                    $crate::OffsetValue::encode_le(&self.$field, &mut bytes[pos..pos + len]);
+                    pos += len;
+                )*
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    offset_struct! {
+        #[derive(Debug, PartialEq)]
+        struct Sample {
+            word: u16,
+            bytes: [u8; 2],
+            dword: i32,
+        }
+    }
+
+    fn encode<T: OffsetValue>(value: &T) -> Vec<u8> {
+        let mut bytes = vec![0; size_of::<T>()];
+        value.encode_le(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn should_encode_numbers_in_little_endian() {
+        assert_eq!(encode(&0x0102u16), vec![0x02, 0x01]);
+        assert_eq!(encode(&-2i32), vec![0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(encode(&1.0f32), vec![0x00, 0x00, 0x80, 0x3f]);
+    }
+
+    #[test]
+    fn should_decode_numbers_from_little_endian() {
+        assert_eq!(u16::decode_le(&[0x02, 0x01]), 0x0102);
+        assert_eq!(i32::decode_le(&[0xfe, 0xff, 0xff, 0xff]), -2);
+        assert_eq!(f64::decode_le(&encode(&1.5f64)), 1.5);
+    }
+
+    #[test]
+    fn should_decode_values_in_place() {
+        let mut value = [0u16; 2];
+        let bytes = [0x02, 0x01, 0x04, 0x03];
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), value.as_mut_ptr() as *mut u8, bytes.len());
+            decode_in_place::<[u16; 2]>(value.as_mut_ptr() as *mut u8);
+        }
+        assert_eq!(value, [0x0102, 0x0304]);
+    }
+
+    #[test]
+    fn should_encode_and_decode_arrays() {
+        let value = [0x0102u16, 0x0304];
+        assert_eq!(encode(&value), vec![0x02, 0x01, 0x04, 0x03]);
+        assert_eq!(<[u16; 2]>::decode_le(&[0x02, 0x01, 0x04, 0x03]), value);
+    }
+
+    #[test]
+    fn should_encode_and_decode_offset_structs() {
+        let value = Sample { word: 0x0102, bytes: [3, 4], dword: 0x05060708 };
+        let bytes = encode(&value);
+        assert_eq!(bytes, vec![0x02, 0x01, 3, 4, 0x08, 0x07, 0x06, 0x05]);
+        assert_eq!(Sample::decode_le(&bytes), value);
+    }
+}
@@ -0,0 +1,312 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::mem::size_of;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::{Exchange, OffsetValue, Result};
+use super::batch::Batch;
+
+/// A numeric offset value that can be compared against a deadband
+pub trait Numeric : OffsetValue {
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! impl_numeric {
+    ($($t:ty),*) => {
+        $(
+            impl Numeric for $t {
+                fn to_f64(self) -> f64 { self as f64 }
+            }
+        )*
+    }
+}
+
+impl_numeric!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+/// A function to decode a numeric value, and the minimum difference reported as a change
+type Deadband = (fn(&[u8]) -> f64, f64);
+
+/// An offset to be watched for changes
+pub struct Watch {
+    offset: u16,
+    len: usize,
+    interval: Option<Duration>,
+    deadband: Option<Deadband>,
+}
+
+impl Watch {
+    /// Watch the given offset, reporting any change of its value
+    /// The length of the offset is inferred from the type `T`.
+    pub fn new<T: OffsetValue>(offset: u16) -> Self {
+        Watch { offset, len: size_of::<T>(), interval: None, deadband: None }
+    }
+
+    /// Watch the given numeric offset, ignoring changes smaller than `deadband`
+    /// Small changes are accumulated, so a slow drift is eventually reported.
+    pub fn numeric<T: Numeric>(offset: u16, deadband: f64) -> Self {
+        fn decode<T: Numeric>(bytes: &[u8]) -> f64 { T::decode_le(bytes).to_f64() }
+        Watch { deadband: Some((decode::<T>, deadband)), ..Self::new::<T>(offset) }
+    }
+
+    /// Poll the offset at the given interval instead of the default one of the watcher
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    fn is_change(&self, old: &[u8], new: &[u8]) -> bool {
+        match self.deadband {
+            Some((decode, deadband)) => (decode(new) - decode(old)).abs() >= deadband,
+            None => old != new,
+        }
+    }
+}
+
+/// A change in the value of a watched offset
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// The offset whose value changed
+    pub offset: u16,
+    /// The bytes of the previous value, or `None` for the first value read
+    pub old: Option<Vec<u8>>,
+    /// The bytes of the new value
+    pub new: Vec<u8>,
+    /// The moment the new value was read
+    pub timestamp: Instant,
+}
+
+impl Change {
+    /// Decode the previous value, if any
+    pub fn old_value<T: OffsetValue>(&self) -> Option<T> {
+        self.old.as_ref().map(|bytes| T::decode_le(bytes))
+    }
+
+    /// Decode the new value
+    pub fn new_value<T: OffsetValue>(&self) -> T {
+        T::decode_le(&self.new)
+    }
+}
+
+/// The state of a watched offset while polling
+struct Entry {
+    watch: Watch,
+    interval: Duration,
+    due: Instant,
+    value: Option<Vec<u8>>,
+}
+
+/// A set of offsets polled for changes in a background thread
+/// The watcher owns the handle used to poll FSUIPC. Once started, the due offsets are read in
+/// a single session at each poll, and their changes are delivered to a callback or a channel.
+pub struct Watcher<E> {
+    handle: E,
+    interval: Duration,
+    watches: Vec<Watch>,
+}
+
+impl<E: Exchange + Send + 'static> Watcher<E> {
+    /// Create a new watcher for the given handle, polling every 100 ms by default
+    pub fn new(handle: E) -> Self {
+        Watcher { handle, interval: Duration::from_millis(100), watches: Vec::new() }
+    }
+
+    /// Set the default polling interval of the offsets
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Add an offset to be watched
+    pub fn watch(&mut self, watch: Watch) {
+        self.watches.push(watch);
+    }
+
+    /// Start polling, delivering each change to the given callback in the background thread
+    pub fn start<F>(self, callback: F) -> Running<E> where F: FnMut(Change) + Send + 'static {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || self.poll(&stop, callback))
+        };
+        Running { stop, thread: Some(thread) }
+    }
+
+    /// Start polling, delivering the changes through the returned channel
+    pub fn start_channel(self) -> (Running<E>, Receiver<Change>) {
+        let (sender, receiver) = mpsc::channel();
+        let running = self.start(move |change| { let _ = sender.send(change); });
+        (running, receiver)
+    }
+
+    fn poll<F: FnMut(Change)>(self, stop: &AtomicBool, mut callback: F) -> (E, Result<()>) {
+        let Watcher { mut handle, interval, watches } = self;
+        let now = Instant::now();
+        let mut entries: Vec<Entry> = watches.into_iter().map(|watch| Entry {
+            interval: watch.interval.unwrap_or(interval),
+            watch,
+            due: now,
+            value: None,
+        }).collect();
+        while !stop.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if let Err(e) = poll_due(&mut handle, &mut entries, now, &mut callback) {
+                return (handle, Err(e));
+            }
+            let next = entries.iter().map(|e| e.due).min().unwrap_or(now + interval);
+            thread::park_timeout(next.saturating_duration_since(Instant::now()));
+        }
+        (handle, Ok(()))
+    }
+}
+
+/// Read the due entries in a single batch and report their changes
+fn poll_due<E, F>(handle: &mut E, entries: &mut [Entry], now: Instant, callback: &mut F)
+    -> Result<()>
+    where E: Exchange, F: FnMut(Change)
+{
+    let due: Vec<usize> = (0..entries.len()).filter(|&i| entries[i].due <= now).collect();
+    if due.is_empty() {
+        return Ok(());
+    }
+    let mut values: Vec<Vec<u8>> = due.iter().map(|&i| vec![0; entries[i].watch.len]).collect();
+    let mut batch = Batch::new(handle.buffer_len());
+    batch.set_auto_split(true);
+    batch.set_coalesce_reads(true);
+    for (&i, value) in due.iter().zip(values.iter_mut()) {
+        batch.read(entries[i].watch.offset, value.as_mut_ptr(), value.len())?;
+    }
+    let prefix = handle.prefix_len();
+    unsafe { batch.process(prefix, |buffer| handle.exchange(buffer))?; }
+    let timestamp = Instant::now();
+    for (i, new) in due.into_iter().zip(values) {
+        let entry = &mut entries[i];
+        entry.due = now + entry.interval;
+        let changed = match entry.value {
+            Some(ref old) => entry.watch.is_change(old, &new),
+            None => true,
+        };
+        if changed {
+            let old = entry.value.replace(new.clone());
+            callback(Change { offset: entry.watch.offset, old, new, timestamp });
+        }
+    }
+    Ok(())
+}
+
+/// A watcher polling in a background thread
+/// The thread is stopped when this object is dropped. Use `stop()` to recover the handle.
+pub struct Running<E> {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<(E, Result<()>)>>,
+}
+
+impl<E> Running<E> {
+    /// Whether the watcher is still polling
+    /// The watcher stops polling when FSUIPC fails, returning the error from `stop()`.
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|t| !t.is_finished())
+    }
+
+    /// Stop polling, returning the handle back or the error that stopped the watcher
+    pub fn stop(mut self) -> Result<E> {
+        let (handle, result) = self.join();
+        result.map(|_| handle)
+    }
+
+    fn join(&mut self) -> (E, Result<()>) {
+        self.stop.store(true, Ordering::SeqCst);
+        let thread = self.thread.take().expect("watcher already stopped");
+        thread.thread().unpark();
+        match thread.join() {
+            Ok(outcome) => outcome,
+            Err(panic) => ::std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl<E> Drop for Running<E> {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let _ = self.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::Error;
+    use super::super::mock::MockHandle;
+    use super::super::shared::SharedHandle;
+
+    fn next(changes: &Receiver<Change>) -> Change {
+        changes.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    #[test]
+    fn should_report_changes() {
+        let mock = SharedHandle::new(MockHandle::new());
+        mock.lock().set(0x3304, &1u32);
+        let mut watcher = Watcher::new(mock.clone());
+        watcher.set_interval(Duration::from_millis(1));
+        watcher.watch(Watch::new::<u32>(0x3304));
+        let (running, changes) = watcher.start_channel();
+
+        let change = next(&changes);
+        assert_eq!(change.offset, 0x3304);
+        assert_eq!(change.old_value::<u32>(), None);
+        assert_eq!(change.new_value::<u32>(), 1);
+
+        mock.lock().set(0x3304, &2u32);
+        let change = next(&changes);
+        assert_eq!(change.old_value::<u32>(), Some(1));
+        assert_eq!(change.new_value::<u32>(), 2);
+        running.stop().unwrap();
+    }
+
+    #[test]
+    fn should_ignore_changes_within_deadband() {
+        let mock = SharedHandle::new(MockHandle::new());
+        let mut watcher = Watcher::new(mock.clone());
+        watcher.watch(Watch::numeric::<i16>(0x02c8, 10.0).with_interval(Duration::from_millis(1)));
+        let (running, changes) = watcher.start_channel();
+        assert_eq!(next(&changes).new_value::<i16>(), 0);
+
+        mock.lock().set(0x02c8, &6i16);
+        mock.lock().set(0x02c8, &-6i16);
+        mock.lock().set(0x02c8, &12i16);
+        let change = next(&changes);
+        assert_eq!(change.old_value::<i16>(), Some(0));
+        assert_eq!(change.new_value::<i16>(), 12);
+        running.stop().unwrap();
+    }
+
+    #[test]
+    fn should_stop_on_failure() {
+        let mock = SharedHandle::new(MockHandle::new());
+        let mut watcher = Watcher::new(mock);
+        watcher.set_interval(Duration::from_millis(1));
+        watcher.watch(Watch::new::<u32>(0xfffe));
+        let running = watcher.start(|_| {});
+        while running.is_running() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        match running.stop() {
+            Err(Error::Rejected { code: 0 }) => {},
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+}
@@ -0,0 +1,530 @@
+//
+// FSUIPC library
+// Copyright (c) 2015 Alvaro Polo
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{BatchSession, Error, Exchange, Handle, Result, Session};
+use super::ipc::{check_request, read_message, MsgHeader, MAX_BUFFER_LEN};
+use super::server::Dispatcher;
+use super::shared::SharedHandle;
+
+/// The TCP port the WideFS server listens to by default
+pub const DEFAULT_PORT: u16 = 9018;
+
+/// The interval the WideFS server polls the watched offsets by default
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The time a WideFS client waits for the offsets it reads for the first time by default
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The WideFS model: the client keeps a mirror of the offsets it reads, and the server keeps it
+// up to date by polling them from FSUIPC and sending their changes. Thus, sessions are
+// processed against the mirror without waiting for the network. The protocol of WideServer is
+// not publicly documented, so this module uses its own messages. All integers are
+// little-endian.
+//
+// * When a client connects, the server sends the `MAGIC` bytes and the version as u32.
+// * The client sends `MSG_WATCH` followed by an offset as u16 and a length as u32 to watch a
+//   range of offsets, and `MSG_WRITE` followed by an offset as u16, a length as u32 and the
+//   bytes to write them.
+// * The server sends `MSG_DATA` followed by an offset as u16, a length as u32 and the bytes
+//   with the contents of a watched range, once it is watched and whenever it changes. If
+//   FSUIPC fails, it sends `MSG_ERROR` followed by the error (see `Error::write_to()`).
+const MAGIC: &[u8; 8] = b"FSUIPCWF";
+const VERSION: u32 = 1;
+const MSG_WATCH: u8 = 1;
+const MSG_WRITE: u8 = 2;
+const MSG_DATA: u8 = 1;
+const MSG_ERROR: u8 = 2;
+
+const OFFSET_SPACE_LEN: usize = 64 * 1024;
+
+/// Serve the given handle to the WideFS clients connecting to the given listener
+/// Each client is served in its own thread, which polls its watched offsets through the
+/// shared handle every `interval`. It only returns if the listener fails.
+pub fn serve<E: Exchange + Send + 'static>(listener: &TcpListener,
+                                           handle: SharedHandle<E>,
+                                           interval: Duration) -> io::Result<()>
+{
+    loop {
+        let (stream, _) = listener.accept()?;
+        let mut handle = handle.clone();
+        thread::spawn(move || serve_client(stream, &mut handle, interval));
+    }
+}
+
+/// A request sent by a WideFS client
+enum Request {
+    Watch { offset: u16, len: usize },
+    Write { offset: u16, data: Vec<u8> },
+}
+
+/// A range of offsets watched by a WideFS client, along with the contents last sent
+struct Watch {
+    range: Range<usize>,
+    sent: Option<Vec<u8>>,
+}
+
+/// Serve the given handle to a connected WideFS client until it disconnects
+/// The offsets watched by the client are polled every `interval`, and their changes are sent
+/// to the client. Writes are applied as soon as they are received.
+pub fn serve_client<E: Exchange>(stream: TcpStream, handle: &mut E, interval: Duration)
+    -> io::Result<()>
+{
+    stream.set_nodelay(true)?;
+    let input = BufReader::new(stream.try_clone()?);
+    let mut output = BufWriter::new(stream);
+    output.write_all(MAGIC)?;
+    output.write_u32::<LittleEndian>(VERSION)?;
+    output.flush()?;
+    let (sender, requests) = mpsc::channel();
+    thread::spawn(move || read_requests(input, sender));
+    let mut watches = Vec::new();
+    let mut failing = false;
+    loop {
+        let mut result = match requests.recv_timeout(interval) {
+            Ok(request) => apply(handle, request, &mut watches),
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        while result.is_ok() {
+            match requests.try_recv() {
+                Ok(request) => result = apply(handle, request, &mut watches),
+                Err(_) => break,
+            }
+        }
+        let result = result.and_then(|()| poll(handle, &mut watches));
+        match result {
+            Ok(changes) => {
+                failing = false;
+                for watch in changes.into_iter().map(|i| &watches[i]) {
+                    let data = watch.sent.as_ref().map_or(&[][..], |data| &data[..]);
+                    output.write_u8(MSG_DATA)?;
+                    output.write_u16::<LittleEndian>(watch.range.start as u16)?;
+                    output.write_u32::<LittleEndian>(data.len() as u32)?;
+                    output.write_all(data)?;
+                }
+            },
+            // Errors are only sent when FSUIPC starts failing, not on every poll.
+            Err(e) => if !failing {
+                failing = true;
+                output.write_u8(MSG_ERROR)?;
+                e.write_to(&mut output)?;
+            },
+        }
+        output.flush()?;
+    }
+}
+
+/// Read the requests of a WideFS client, sending them to the given channel
+/// Malformed requests close the connection.
+fn read_requests<R: Read>(mut input: R, requests: Sender<Request>) -> io::Result<()> {
+    loop {
+        let kind = input.read_u8()?;
+        let offset = input.read_u16::<LittleEndian>()?;
+        let len = input.read_u32::<LittleEndian>()? as usize;
+        if offset as usize + len > OFFSET_SPACE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "range out of offset space"));
+        }
+        let request = match kind {
+            MSG_WATCH => Request::Watch { offset, len },
+            MSG_WRITE => {
+                let mut data = vec![0; len];
+                input.read_exact(&mut data)?;
+                Request::Write { offset, data }
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown request")),
+        };
+        if requests.send(request).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn apply<E: Exchange>(handle: &mut E, request: Request, watches: &mut Vec<Watch>) -> Result<()> {
+    match request {
+        Request::Watch { offset, len } => {
+            let range = offset as usize..offset as usize + len;
+            if !watches.iter().any(|w| w.range == range) {
+                watches.push(Watch { range, sent: None });
+            }
+            Ok(())
+        },
+        Request::Write { offset, data } => {
+            let range = offset as usize..offset as usize + data.len();
+            // The watched ranges overlapping the write are sent again, even if they do not
+            // change, so clients do not keep a value FSUIPC did not accept.
+            for watch in watches.iter_mut() {
+                if watch.range.start < range.end && range.start < watch.range.end {
+                    watch.sent = None;
+                }
+            }
+            let buffer_len = handle.buffer_len();
+            let mut session = BatchSession::new(handle, buffer_len);
+            session.set_auto_split(true);
+            session.write_bytes(offset, data.as_ptr(), data.len())?;
+            session.process()?;
+            Ok(())
+        },
+    }
+}
+
+/// Read the watched offsets, returning the indices of the ones that changed since last sent
+fn poll<E: Exchange>(handle: &mut E, watches: &mut [Watch]) -> Result<Vec<usize>> {
+    let mut buffers: Vec<Vec<u8>> = watches.iter().map(|w| vec![0; w.range.len()]).collect();
+    {
+        let buffer_len = handle.buffer_len();
+        let mut session = BatchSession::new(&mut *handle, buffer_len);
+        session.set_auto_split(true);
+        for (watch, buffer) in watches.iter().zip(buffers.iter_mut()) {
+            session.read_bytes(watch.range.start as u16, buffer.as_mut_ptr(), buffer.len())?;
+        }
+        session.process()?;
+    }
+    let mut changes = Vec::new();
+    for (i, (watch, buffer)) in watches.iter_mut().zip(buffers).enumerate() {
+        if watch.sent.as_ref() != Some(&buffer) {
+            watch.sent = Some(buffer);
+            changes.push(i);
+        }
+    }
+    Ok(changes)
+}
+
+/// The offsets mirrored by a WideFS client
+struct Mirror {
+    data: Vec<u8>,
+    received: Vec<Range<usize>>,
+    error: Option<Error>,
+    closed: bool,
+}
+
+/// The mirror shared between a `WideHandle` and the thread receiving its updates
+struct Shared {
+    mirror: Mutex<Mirror>,
+    updated: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Mirror> {
+        self.mirror.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A handle to FSUIPC through a WideFS server
+/// As WideClient does, it keeps a mirror of the offsets it reads, which the server updates
+/// whenever they change. Sessions are processed against the mirror: reads are served from it,
+/// and writes are applied to it and forwarded to the server. Only the first read of an offset
+/// waits for the server to send its contents.
+pub struct WideHandle {
+    output: BufWriter<TcpStream>,
+    shared: Arc<Shared>,
+    watched: Vec<Range<usize>>,
+    timeout: Duration,
+}
+
+impl WideHandle {
+    /// Connect to the WideFS server at the given address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut input = BufReader::new(stream.try_clone()?);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        let version = input.read_u32::<LittleEndian>()?;
+        if &magic != MAGIC || version != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a WideFS server").into());
+        }
+        let shared = Arc::new(Shared {
+            mirror: Mutex::new(Mirror {
+                data: vec![0; OFFSET_SPACE_LEN],
+                received: Vec::new(),
+                error: None,
+                closed: false,
+            }),
+            updated: Condvar::new(),
+        });
+        {
+            let shared = shared.clone();
+            thread::spawn(move || receive_updates(input, &shared));
+        }
+        Ok(WideHandle {
+            output: BufWriter::new(stream),
+            shared,
+            watched: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Set the time to wait for the offsets read for the first time
+    /// If their contents do not arrive in time, the exchange fails with `Error::Timeout`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Send the requests of the buffer to the server, returning the ranges it reads
+    fn forward(&mut self, buffer: &[u8]) -> Result<Vec<Range<usize>>> {
+        let mut reads = Vec::new();
+        let mut start = 0;
+        loop {
+            let (header, body) = read_message(buffer, start)?;
+            match header {
+                MsgHeader::ReadStateData { offset, len, .. } => {
+                    let range = offset as usize..offset as usize + len;
+                    // Reads out of the offset space are left to the mirror to reject.
+                    if range.end <= OFFSET_SPACE_LEN {
+                        if !self.watched.contains(&range) {
+                            self.output.write_u8(MSG_WATCH)?;
+                            self.output.write_u16::<LittleEndian>(offset)?;
+                            self.output.write_u32::<LittleEndian>(len as u32)?;
+                            self.watched.push(range.clone());
+                        }
+                        reads.push(range);
+                    }
+                },
+                MsgHeader::WriteStateData { offset, len } => {
+                    if offset as usize + len <= OFFSET_SPACE_LEN {
+                        self.output.write_u8(MSG_WRITE)?;
+                        self.output.write_u16::<LittleEndian>(offset)?;
+                        self.output.write_u32::<LittleEndian>(len as u32)?;
+                        self.output.write_all(&buffer[body.clone()])?;
+                    }
+                },
+                MsgHeader::TerminationMark => break,
+            }
+            start = body.end;
+        }
+        self.output.flush()?;
+        Ok(reads)
+    }
+}
+
+/// Receive the updates sent by the server into the mirror until the connection is closed
+fn receive_updates<R: Read>(mut input: R, shared: &Shared) {
+    loop {
+        let update = match input.read_u8() {
+            Ok(MSG_DATA) => read_data(&mut input),
+            Ok(_) => Error::read_from(&mut input).map(Err),
+            Err(e) => Err(e.into()),
+        };
+        let mut mirror = shared.lock();
+        match update {
+            Ok(Ok((offset, data))) => {
+                let range = offset as usize..offset as usize + data.len();
+                if let Some(dest) = mirror.data.get_mut(range.clone()) {
+                    dest.copy_from_slice(&data);
+                    if !mirror.received.contains(&range) {
+                        mirror.received.push(range);
+                    }
+                }
+            },
+            Ok(Err(e)) => mirror.error = Some(e),
+            Err(_) => mirror.closed = true,
+        }
+        shared.updated.notify_all();
+        if mirror.closed {
+            return;
+        }
+    }
+}
+
+fn read_data<R: Read>(input: &mut R) -> Result<Result<(u16, Vec<u8>)>> {
+    let offset = input.read_u16::<LittleEndian>()?;
+    let len = input.read_u32::<LittleEndian>()? as usize;
+    if len > OFFSET_SPACE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "update too long").into());
+    }
+    let mut data = vec![0; len];
+    input.read_exact(&mut data)?;
+    Ok(Ok((offset, data)))
+}
+
+impl Exchange for WideHandle {
+    fn buffer_len(&self) -> usize { MAX_BUFFER_LEN }
+
+    fn exchange(&mut self, buffer: &mut [u8]) -> Result<()> {
+        check_request(buffer)?;
+        let reads = self.forward(buffer)?;
+        let deadline = Instant::now() + self.timeout;
+        let mut mirror = self.shared.lock();
+        loop {
+            if let Some(e) = mirror.error.take() {
+                return Err(e);
+            }
+            if mirror.closed {
+                let e = io::Error::new(io::ErrorKind::ConnectionAborted, "WideFS server is gone");
+                return Err(e.into());
+            }
+            if reads.iter().all(|range| mirror.received.contains(range)) {
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            mirror = self.shared.updated.wait_timeout(mirror, deadline - now)
+                .unwrap_or_else(|e| e.into_inner()).0;
+        }
+        Dispatcher::new(&mut mirror.data[..]).process(buffer)
+    }
+}
+
+impl Drop for WideHandle {
+    fn drop(&mut self) {
+        // Closing the connection stops the thread receiving the updates.
+        let _ = self.output.get_ref().shutdown(Shutdown::Both);
+    }
+}
+
+impl<'a> Handle<'a> for WideHandle {
+    type Sess = WideSession<'a>;
+
+    fn session(&'a mut self) -> WideSession<'a> {
+        BatchSession::new(self, MAX_BUFFER_LEN)
+    }
+}
+
+/// A session of a `WideHandle`
+pub type WideSession<'a> = BatchSession<&'a mut WideHandle>;
+
+#[cfg(test)]
+mod test {
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use super::super::mock::MockHandle;
+
+    fn start_server(mock: MockHandle) -> (SharedHandle<MockHandle>, WideHandle) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = SharedHandle::new(mock);
+        {
+            let handle = handle.clone();
+            thread::spawn(move || serve(&listener, handle, Duration::from_millis(5)));
+        }
+        (handle, WideHandle::connect(addr).unwrap())
+    }
+
+    fn read_u32(wide: &mut WideHandle, offset: u16) -> Result<u32> {
+        let mut value = 0u32;
+        let mut session = wide.session();
+        session.read(offset, &mut value)?;
+        session.process()?;
+        Ok(value)
+    }
+
+    fn eventually<F: FnMut() -> bool>(mut condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "condition not met in time");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn should_read_and_write_offsets_through_server() {
+        let mut mock = MockHandle::new();
+        mock.set(0x3304, &0x49505553u32);
+        let (server, mut wide) = start_server(mock);
+        let mut fsuipc_ver = 0u32;
+        let mut qnh = 0u16;
+        {
+            let mut session = wide.session();
+            session.read(0x3304, &mut fsuipc_ver).unwrap();
+            session.write(0x0330, &(1020u16 * 16)).unwrap();
+            session.read(0x0330, &mut qnh).unwrap();
+            session.process().unwrap();
+        }
+        assert_eq!(fsuipc_ver, 0x49505553);
+        assert_eq!(qnh, 1020 * 16);
+        eventually(|| server.lock().get::<u16>(0x0330) == 1020 * 16);
+    }
+
+    #[test]
+    fn should_receive_changes_of_read_offsets() {
+        let mut mock = MockHandle::new();
+        mock.set(0x3304, &1u32);
+        let (server, mut wide) = start_server(mock);
+        assert_eq!(read_u32(&mut wide, 0x3304).unwrap(), 1);
+        server.lock().set(0x3304, &2u32);
+        eventually(|| read_u32(&mut wide, 0x3304).unwrap() == 2);
+    }
+
+    #[test]
+    fn should_reject_reads_out_of_offset_space() {
+        let (_, mut wide) = start_server(MockHandle::new());
+        match read_u32(&mut wide, 0xfffe) {
+            Err(Error::Rejected { .. }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    fn start_silent_server() -> (TcpListener, WideHandle) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || WideHandle::connect(addr).unwrap());
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(MAGIC).unwrap();
+        stream.write_u32::<LittleEndian>(VERSION).unwrap();
+        let wide = client.join().unwrap();
+        thread::spawn(move || io::copy(&mut stream, &mut io::sink()));
+        (listener, wide)
+    }
+
+    #[test]
+    fn should_time_out_when_server_does_not_send_read_offsets() {
+        let (_listener, mut wide) = start_silent_server();
+        wide.set_timeout(Duration::from_millis(20));
+        match read_u32(&mut wide, 0x3304) {
+            Err(Error::Timeout) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_fail_when_server_is_gone() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(MAGIC).unwrap();
+            stream.write_u32::<LittleEndian>(VERSION).unwrap();
+        });
+        let mut wide = WideHandle::connect(addr).unwrap();
+        match read_u32(&mut wide, 0x3304) {
+            Err(Error::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn should_fail_to_connect_to_other_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"not a WideFS server").unwrap();
+        });
+        match WideHandle::connect(addr) {
+            Err(Error::Io(ref e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+}